@@ -0,0 +1,53 @@
+/* Build script
+
+Copyright (c) 2020 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+ */
+
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+/// Collect the files under dir.
+fn sourcefiles(dir: &Path, files: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            sourcefiles(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+/// Hash the sources and the manifest into COVID19KS_SOURCE_HASH, which the build cache
+/// uses to tell whether the code that produced a chart has changed.  Unlike a git
+/// revision, this also changes with uncommitted edits.
+fn main() {
+    let mut files = vec![PathBuf::from("Cargo.toml")];
+    sourcefiles(Path::new("src"), &mut files);
+    // Sorted so the hash doesn't depend on directory order
+    files.sort();
+    let mut hasher = DefaultHasher::new();
+    for file in files {
+        file.hash(&mut hasher);
+        fs::read(&file).unwrap().hash(&mut hasher);
+    }
+    println!("cargo:rustc-env=COVID19KS_SOURCE_HASH={:016x}", hasher.finish());
+    println!("cargo:rerun-if-changed=src");
+    println!("cargo:rerun-if-changed=Cargo.toml");
+}
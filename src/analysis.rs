@@ -17,130 +17,618 @@ Copyright (c) 2020 John Goerzen
 
  */
 
+use chrono::Datelike;
+use crate::smoothing::Partial;
+use covid19db::dateutil::*;
 use std::collections::HashMap;
+use std::hash::Hash;
+
+/// Names of the weekdays, indexed as by weekday()
+pub const WEEKDAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+/// z value for an approximately 95% interval
+pub const Z_95: f64 = 1.96;
+
+/// A series with an interval around it, such as a forecast with its prediction interval
+#[derive(Debug, Clone, Default)]
+pub struct Interval {
+    pub center: HashMap<i32, f64>,
+    pub lower: HashMap<i32, f64>,
+    pub upper: HashMap<i32, f64>,
+}
 
 /// Find the largest key in the HashMap
 pub fn largestkey<T: Ord, U>(hm: &HashMap<T, U>) -> Option<&T> {
     hm.keys().max()
 }
 
-/// Populate the simple moving average in the second element of the list, modifying it in-place.
-pub fn calcsimplema(hm: &HashMap<i32, f64>, window: usize) -> HashMap<i32, f64> {
-    let mut history: Vec<f64> = Vec::new();
-    let mut keys: Vec<i32> = hm.keys().map(|x| x.clone()).collect();
-    keys.sort();
-    let mut rethm = HashMap::new();
-    let mut previous = None;
-    for key in keys.into_iter() {
-        match hm.get(&key) {
-            Some(val) => {
-                if let Some(p) = previous {
-                    // Make sure we have no gaps in the data
-                    assert_eq!(p + 1, key);
-                }
-                history.push(*val);
-                if history.len() > window {
-                    history.remove(0);
-                }
-                rethm.insert(key, history.iter().sum::<f64>() / (window as f64));
-                previous = Some(key);
-            }
-            None => (),
+/// Find the first day on which the value reached threshold, if it ever did.  Use this to
+/// align series on days since they crossed the threshold.
+pub fn firstcrossing(hm: &HashMap<i32, f64>, threshold: f64) -> Option<i32> {
+    hm.iter()
+        .filter(|(_, val)| **val >= threshold)
+        .map(|(key, _)| *key)
+        .min()
+}
+
+/// Ways of computing test positivity.  Agencies differ on what counts as a test and on
+/// how to average over the window, so their figures don't match ours or each other's.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Positivity {
+    /// Positive results over all test results, summed over the window.  This is Covid
+    /// Tracking's definition, and OWID's new cases over new tests.
+    Tests,
+    /// The mean of each day's positive results over test results
+    DailyMean,
+    /// Positive viral (PCR) tests over viral tests, leaving out antibody tests
+    ViralTests,
+    /// People with a positive viral test over people tested
+    People,
+}
+
+impl Positivity {
+    pub const ALL: [Positivity; 4] = [Positivity::Tests, Positivity::DailyMean, Positivity::ViralTests, Positivity::People];
+
+    /// Label for charts
+    pub fn name(&self) -> &'static str {
+        match self {
+            Positivity::Tests => "Positive tests / tests",
+            Positivity::DailyMean => "Mean of daily ratios",
+            Positivity::ViralTests => "Viral tests only",
+            Positivity::People => "People tested",
+        }
+    }
+
+    /// The (positive, total) columns this method needs from covidtracking, and whether they
+    /// are cumulative rather than daily
+    pub fn covidtracking_columns(&self) -> (&'static str, &'static str, bool) {
+        match self {
+            Positivity::Tests | Positivity::DailyMean => ("positiveIncrease", "totalTestResultsIncrease", false),
+            Positivity::ViralTests => ("positiveTestsViral", "totalTestsViral", true),
+            Positivity::People => ("positiveCasesViral", "totalTestsPeopleViral", true),
+        }
+    }
+
+    /// The (positive, total) columns this method needs from owid, if it has them.  OWID
+    /// doesn't separate viral from antibody tests, and counts people tested rather than
+    /// tests in only some countries.
+    pub fn owid_columns(&self) -> Option<(&'static str, &'static str)> {
+        match self {
+            Positivity::Tests | Positivity::DailyMean => Some(("new_cases", "new_tests")),
+            Positivity::ViralTests | Positivity::People => None,
         }
     }
-    rethm
 }
 
-/// Populate the simple sum in the second element of the list
-pub fn calcsimplesum(hm: &HashMap<i32, f64>, window: usize, allowpartial: bool) -> HashMap<i32, f64> {
-    let mut history: Vec<f64> = Vec::new();
-    let mut keys: Vec<i32> = hm.keys().map(|x| x.clone()).collect();
-    keys.sort();
+/// Percent of tests positive over a rolling window of daily (pos, total) test data, by
+/// the given method.  Days missing from hm are skipped, and the window covers whatever
+/// days it has.  If allowpartial is set, days before the first full window are included.
+pub fn calcpositivity(hm: &HashMap<i32, (i64, i64)>, method: Positivity, window: usize, allowpartial: bool) -> HashMap<i32, f64> {
+    let (first, last) = match (hm.keys().min(), hm.keys().max()) {
+        (Some(first), Some(last)) => (*first, *last),
+        _ => return HashMap::new(),
+    };
     let mut rethm = HashMap::new();
-    let mut previous = None;
-    for key in keys.into_iter() {
-        match hm.get(&key) {
-            Some(val) => {
-                if let Some(p) = previous {
-                    // Make sure we have no gaps in the data
-                    assert_eq!(p + 1, key);
-                }
-                history.push(*val);
-                if history.len() > window {
-                    history.remove(0);
+    for day in first..=last {
+        let start = day - window as i32 + 1;
+        if !allowpartial && start < first {
+            continue;
+        }
+        let days: Vec<(i64, i64)> = (start..=day).filter_map(|d| hm.get(&d)).copied().collect();
+        let rate = match method {
+            Positivity::DailyMean => {
+                // A day with no tests has no ratio
+                let ratios: Vec<f64> = days
+                    .iter()
+                    .filter(|(_, tot)| *tot > 0)
+                    .map(|(pos, tot)| (*pos as f64) / (*tot as f64))
+                    .collect();
+                match ratios.is_empty() {
+                    true => continue,
+                    false => ratios.iter().sum::<f64>() / (ratios.len() as f64),
                 }
-                if allowpartial || history.len() == window {
-                    rethm.insert(key, history.iter().sum::<f64>());
+            }
+            _ => {
+                let (pos, tot) = days.iter().fold((0, 0), |(pos1, tot1), (pos2, tot2)| (pos1 + pos2, tot1 + tot2));
+                match tot > 0 {
+                    true => (pos as f64) / (tot as f64),
+                    false => continue,
                 }
-                previous = Some(key);
             }
-            None => (),
-        }
+        };
+        rethm.insert(day, 100f64 * rate);
     }
     rethm
 }
 
-/// Like calcsimplesum, but for (pos, total) test data
-pub fn calcsimplerate_testdata(hm: &HashMap<i32, (i64, i64)>, window: usize, allowpartial: bool) -> HashMap<i32, f64> {
-    let mut history: Vec<(i64, i64)> = Vec::new();
-    let mut keys: Vec<i32> = hm.keys().map(|x| x.clone()).collect();
-    keys.sort();
-    let mut rethm = HashMap::new();
-    let mut previous = None;
-    for key in keys.into_iter() {
-        match hm.get(&key) {
-            Some(val) => {
-                if let Some(p) = previous {
-                    // Make sure we have no gaps in the data
-                    assert_eq!(p + 1, key);
-                }
-                history.push(*val);
-                if history.len() > window {
-                    history.remove(0);
-                }
-                if allowpartial || history.len() == window {
-                    let sum = history.iter().fold((0, 0), |(pos1, tot1),(pos2, tot2)| (pos1 + pos2, tot1 + tot2));
-                    rethm.insert(key, 100f64 * (sum.0 as f64) / (sum.1 as f64));
-                }
-                previous = Some(key);
+/// Day of the week of the given day, 0 for Monday through 6 for Sunday
+pub fn weekday(day: i32) -> usize {
+    day_to_nd(day).weekday().num_days_from_monday() as usize
+}
+
+/// Number of days over which detectcadence looks at reporting; four of each weekday.
+pub const CADENCE_WINDOW: i32 = 28;
+
+/// Fewest weekdays a source must report on, and fewest it must skip, to be taken as
+/// batching its reports.  A source reporting on just one weekday is too hard to tell from
+/// a sparse daily series that happens to have a busy weekday, and one skipping just one
+/// weekday gains little from redistribution.
+pub const BATCH_MIN_DAYS: usize = 2;
+
+/// The weekdays a source reports on over first..=last, if it batches its reports there:
+/// every weekday is either nonzero on each of its days in the window or zero on each of
+/// them, and there are at least BATCH_MIN_DAYS of each kind.  None if it doesn't, as for a
+/// daily series where zeros fall on whatever day they happen to.
+fn windowcadence(hm: &HashMap<i32, f64>, first: i32, last: i32) -> Option<[bool; 7]> {
+    let mut days = [0; 7];
+    let mut reported = [0; 7];
+    for key in first..=last {
+        if let Some(val) = hm.get(&key) {
+            days[weekday(key)] += 1;
+            if *val != 0.0 {
+                reported[weekday(key)] += 1;
             }
-            None => (),
+        }
+    }
+    let mut cadence = [false; 7];
+    for i in 0..7 {
+        // Too little of the window has data to tell
+        if days[i] < 2 {
+            return None;
+        }
+        match reported[i] {
+            0 => cadence[i] = false,
+            n if n == days[i] => cadence[i] = true,
+            _ => return None,
+        }
+    }
+    let reporting = cadence.iter().filter(|x| **x).count();
+    match reporting >= BATCH_MIN_DAYS && 7 - reporting >= BATCH_MIN_DAYS {
+        true => Some(cadence),
+        false => None,
+    }
+}
+
+/// Detect which weekdays a source reports on, as of each day in hm.  Sources change their
+/// schedules -- Kansas counties went from weekday-only reports back to daily ones at
+/// different times -- so this looks at the CADENCE_WINDOW days ending on each day, or
+/// failing that, the ones starting on it, for a consistent batch schedule; see
+/// windowcadence.  Days without one in either window are taken as reported daily.  That
+/// includes sparse daily series, such as small counties or deaths, whose zeros don't keep
+/// to a schedule, and batched ones around a holiday that skipped a reporting day.
+pub fn detectcadence(hm: &HashMap<i32, f64>) -> HashMap<i32, [bool; 7]> {
+    hm.keys()
+        .map(|day| {
+            let cadence = windowcadence(hm, day - CADENCE_WINDOW + 1, *day)
+                .or_else(|| windowcadence(hm, *day, day + CADENCE_WINDOW - 1))
+                .unwrap_or([true; 7]);
+            (*day, cadence)
+        })
+        .collect()
+}
+
+/// Spread batch reports back over the days they cover.  A report on a day that was a
+/// reporting day as of then is taken to cover that day and the zero days just before it
+/// that fall on non-reporting weekdays, and is divided evenly among them.  Days reported on
+/// their own are left alone, so this does nothing to a source that reports daily, even if
+/// it didn't always.  Use this before smoothing.
+pub fn redistribute(hm: &HashMap<i32, f64>, cadence: &HashMap<i32, [bool; 7]>) -> HashMap<i32, f64> {
+    let mut rethm = hm.clone();
+    for (key, val) in hm.iter() {
+        let cadence = match cadence.get(key) {
+            Some(cadence) if cadence[weekday(*key)] => cadence,
+            _ => continue,
+        };
+        let mut first = *key;
+        while !cadence[weekday(first - 1)] && hm.get(&(first - 1)) == Some(&0.0) {
+            first -= 1;
+        }
+        let share = val / ((key - first + 1) as f64);
+        for day in first..=*key {
+            rethm.insert(day, share);
         }
     }
     rethm
 }
 
-/// untested
-#[allow(dead_code)]
-pub fn calcweightedma(hm: &HashMap<i32, f64>, window: usize) -> HashMap<i32, f64> {
-    let mut history: Vec<f64> = Vec::new();
-    let mut keys: Vec<i32> = hm.keys().map(|x| x.clone()).collect();
-    keys.sort();
-    let mut rethm = HashMap::new();
-    let mut previous = None;
-    for key in keys.into_iter() {
-        match hm.get(&key) {
-            Some(val) => {
-                if let Some(p) = previous {
-                    // Make sure we have no gaps in the data
-                    assert_eq!(p + 1, key);
-                }
-                history.push(*val);
-                if history.len() > window {
-                    history.remove(0);
-                }
-                let mut sum = 0.0;
-                for (item, index) in history.iter().zip(1..) {
-                    sum += item * (index as f64);
-                }
-                rethm.insert(
-                    key,
-                    sum / ((history.len() * (history.len() + 1)) as f64 / 2.0),
-                );
-                previous = Some(key);
-            },
-            None => (),
+/// redistribute hm according to its own detected cadence.
+pub fn debatch(hm: &HashMap<i32, f64>) -> HashMap<i32, f64> {
+    redistribute(hm, &detectcadence(hm))
+}
+
+/// The mean value on each weekday relative to the mean over all days; 1.0 means no
+/// weekday effect.  Useful to see reporting artifacts.
+pub fn weekdayeffect(hm: &HashMap<i32, f64>) -> [f64; 7] {
+    let mut sums = [0.0; 7];
+    let mut counts = [0; 7];
+    for (key, val) in hm.iter() {
+        sums[weekday(*key)] += val;
+        counts[weekday(*key)] += 1;
+    }
+    let mean = hm.values().sum::<f64>() / (hm.len() as f64);
+    let mut effect = [0.0; 7];
+    for i in 0..7 {
+        if counts[i] > 0 {
+            effect[i] = sums[i] / (counts[i] as f64) / mean;
+        }
+    }
+    effect
+}
+
+/// Fill in days missing from hm, between its first and last days, by interpolating
+/// linearly between the days on either side.  The smoothers need a series without gaps.
+pub fn interpolate(hm: &HashMap<i32, f64>) -> HashMap<i32, f64> {
+    let mut keys: Vec<i32> = hm.keys().copied().collect();
+    keys.sort_unstable();
+    let mut rethm = hm.clone();
+    for pair in keys.windows(2) {
+        let (start, end) = (pair[0], pair[1]);
+        let step = (hm[&end] - hm[&start]) / ((end - start) as f64);
+        for day in (start + 1)..end {
+            rethm.insert(day, hm[&start] + step * ((day - start) as f64));
         }
     }
     rethm
 }
+
+/// a - b, for the days that are in both
+pub fn calcdifference(a: &HashMap<i32, f64>, b: &HashMap<i32, f64>) -> HashMap<i32, f64> {
+    a.iter()
+        .filter_map(|(key, aval)| b.get(key).map(|bval| (*key, aval - bval)))
+        .collect()
+}
+
+/// Pearson correlation of a, lag days earlier, with b, over the days both have.  None if
+/// there are fewer than 3 such days or either is constant over them.
+pub fn crosscorrelation(a: &HashMap<i32, f64>, b: &HashMap<i32, f64>, lag: i32) -> Option<f64> {
+    let pairs: Vec<(f64, f64)> = b
+        .iter()
+        .filter_map(|(key, bval)| a.get(&(key - lag)).map(|aval| (*aval, *bval)))
+        .collect();
+    if pairs.len() < 3 {
+        return None;
+    }
+    let n = pairs.len() as f64;
+    let amean = pairs.iter().map(|(a, _)| a).sum::<f64>() / n;
+    let bmean = pairs.iter().map(|(_, b)| b).sum::<f64>() / n;
+    let cov: f64 = pairs.iter().map(|(a, b)| (a - amean) * (b - bmean)).sum();
+    let avar: f64 = pairs.iter().map(|(a, _)| (a - amean).powi(2)).sum();
+    let bvar: f64 = pairs.iter().map(|(_, b)| (b - bmean).powi(2)).sum();
+    if avar == 0.0 || bvar == 0.0 {
+        return None;
+    }
+    Some(cov / (avar * bvar).sqrt())
+}
+
+/// The lag, from 0 to maxlag days, at which a best predicts b by crosscorrelation, if
+/// there is any.  Pass smoothed series; day-to-day noise swamps the signal otherwise.
+pub fn bestlag(a: &HashMap<i32, f64>, b: &HashMap<i32, f64>, maxlag: i32) -> Option<i32> {
+    (0..=maxlag)
+        .filter_map(|lag| crosscorrelation(a, b, lag).map(|r| (lag, r)))
+        .fold(None, |best: Option<(i32, f64)>, (lag, r)| match best {
+            Some((_, bestr)) if bestr >= r => best,
+            _ => Some((lag, r)),
+        })
+        .map(|(lag, _)| lag)
+}
+
+/// The lag, from -maxlag to maxlag days, at which a best correlates with b, along with the
+/// correlation there.  A positive lag means a leads b; a negative one, that b leads a.
+/// Pass smoothed series, as for bestlag.
+pub fn leadlag(a: &HashMap<i32, f64>, b: &HashMap<i32, f64>, maxlag: i32) -> Option<(i32, f64)> {
+    (-maxlag..=maxlag)
+        .filter_map(|lag| crosscorrelation(a, b, lag).map(|r| (lag, r)))
+        .fold(None, |best: Option<(i32, f64)>, (lag, r)| match best {
+            // Prefer the shortest lag among equals, and of two as short, the negative one
+            Some((bestlag, bestr)) if bestr > r || (bestr == r && bestlag.abs() <= lag.abs()) => best,
+            _ => Some((lag, r)),
+        })
+}
+
+/// Case fatality ratio, in percent, with deaths lagged behind cases: the deaths over the
+/// window days ending on each day, over the cases over the window days ending lag days
+/// earlier.  Days with no cases in their window are left out.
+pub fn laggedcfr(cases: &HashMap<i32, f64>, deaths: &HashMap<i32, f64>, lag: i32, window: i32) -> HashMap<i32, f64> {
+    let windowsum = |hm: &HashMap<i32, f64>, last: i32| -> Option<f64> {
+        (last - window + 1..=last).map(|day| hm.get(&day).copied()).sum()
+    };
+    deaths
+        .keys()
+        .filter_map(|day| match (windowsum(deaths, *day), windowsum(cases, day - lag)) {
+            (Some(d), Some(c)) if c > 0.0 => Some((*day, 100.0 * d / c)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// 95% confidence interval around a smoothed rate per 100k pop., treating the counts behind
+/// it as Poisson.  center is the smoothed rate, and each of its days gets the interval for
+/// the counts over the window days ending that day (or as many of them as there are);
+/// if mean is set, it is for the mean over those days rather than their sum.  partial is
+/// how the smoother treated windows reaching past the start of the data, which decides
+/// what such a mean was divided by.  The bounds use Byar's approximation to the exact
+/// interval, which is close even for small counts.
+pub fn poissoninterval(
+    center: &HashMap<i32, f64>,
+    counts: &HashMap<i32, f64>,
+    population: f64,
+    window: usize,
+    mean: bool,
+    partial: Partial,
+) -> Interval {
+    let mut ret = Interval { center: center.clone(), ..Default::default() };
+    for day in center.keys() {
+        let available: Vec<f64> = (day - window as i32 + 1..=*day).filter_map(|d| counts.get(&d).copied()).collect();
+        if available.is_empty() {
+            continue;
+        }
+        // Corrections can make the sum negative; there can't be fewer than no cases
+        let k = available.iter().sum::<f64>().round().max(0.0);
+        let lower = match k > 0.0 {
+            true => k * (1.0 - 1.0 / (9.0 * k) - Z_95 / (3.0 * k.sqrt())).powi(3),
+            false => 0.0,
+        };
+        let upper = (k + 1.0) * (1.0 - 1.0 / (9.0 * (k + 1.0)) + Z_95 / (3.0 * (k + 1.0).sqrt())).powi(3);
+        // Zero fills in the missing days, so the mean is still over the whole window
+        let days = match partial {
+            Partial::Zero => window,
+            _ => available.len(),
+        };
+        let scale = match mean {
+            true => 100000.0 / population / (days as f64),
+            false => 100000.0 / population,
+        };
+        ret.lower.insert(*day, lower * scale);
+        ret.upper.insert(*day, upper * scale);
+    }
+    ret
+}
+
+/// Empirical Bayes estimates of the rates of a set of regions, each given as (count,
+/// exposure) with rate count / exposure.  Each rate is shrunk toward the overall rate by
+/// how little its own count tells us, so that small regions aren't ranked first or last
+/// by chance.  This is Marshall's global method-of-moments estimator.  Regions with no
+/// exposure are left out.
+pub fn ebshrink<K: Clone + Eq + Hash>(regions: &HashMap<K, (f64, f64)>) -> HashMap<K, f64> {
+    let regions: Vec<(&K, f64, f64)> = regions
+        .iter()
+        .filter(|(_, (_, exposure))| *exposure > 0.0)
+        .map(|(key, (count, exposure))| (key, count / exposure, *exposure))
+        .collect();
+    if regions.is_empty() {
+        return HashMap::new();
+    }
+    let totalexposure: f64 = regions.iter().map(|(_, _, exposure)| exposure).sum();
+    let mean = regions.iter().map(|(_, rate, exposure)| rate * exposure).sum::<f64>() / totalexposure;
+    let variance = regions.iter().map(|(_, rate, exposure)| exposure * (rate - mean).powi(2)).sum::<f64>() / totalexposure;
+    // Variance among the true rates: what is left after that expected from chance alone
+    let between = (variance - mean / (totalexposure / regions.len() as f64)).max(0.0);
+    regions
+        .into_iter()
+        .map(|(key, rate, exposure)| {
+            let weight = match between > 0.0 {
+                true => between / (between + mean / exposure),
+                false => 0.0,
+            };
+            (key.clone(), mean + weight * (rate - mean))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{assert_close, series};
+
+    #[test]
+    fn positivity_methods() {
+        // Day 102 has no tests and day 104 is missing
+        let hm: HashMap<i32, (i64, i64)> =
+            vec![(100, (1, 10)), (101, (9, 30)), (102, (0, 0)), (103, (6, 20)), (105, (2, 10)), (106, (0, 0)), (107, (0, 0))]
+                .into_iter()
+                .collect();
+        let tests = calcpositivity(&hm, Positivity::Tests, 2, false);
+        assert_close(&tests, &[(101, 25.0), (102, 30.0), (103, 30.0), (104, 30.0), (105, 20.0), (106, 20.0)]);
+        // The mean of the daily ratios weighs a day with few tests as much as one with many
+        let dailymean = calcpositivity(&hm, Positivity::DailyMean, 2, false);
+        assert_close(&dailymean, &[(101, 20.0), (102, 30.0), (103, 30.0), (104, 30.0), (105, 20.0), (106, 20.0)]);
+        // Cumulative columns are converted to daily ones before this, so the others match Tests
+        assert_eq!(calcpositivity(&hm, Positivity::People, 2, false), tests);
+        let partial = calcpositivity(&hm, Positivity::Tests, 2, true);
+        assert_close(&partial, &[(100, 10.0), (101, 25.0), (102, 30.0), (103, 30.0), (104, 30.0), (105, 20.0), (106, 20.0)]);
+        assert!(calcpositivity(&HashMap::new(), Positivity::Tests, 2, true).is_empty());
+    }
+
+    #[test]
+    fn interpolate_gaps() {
+        let hm: HashMap<i32, f64> = vec![(100, 2.0), (101, 4.0), (104, 10.0)].into_iter().collect();
+        assert_close(&interpolate(&hm), &[(100, 2.0), (101, 4.0), (102, 6.0), (103, 8.0), (104, 10.0)]);
+        assert!(interpolate(&HashMap::new()).is_empty());
+    }
+
+    /// An irregular signal, so that it correlates with itself only at lag 0
+    fn irregular(days: usize) -> HashMap<i32, f64> {
+        series(&(0..days).map(|i| ((i * i + 3 * i) % 17) as f64).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn crosscorrelation_lagged() {
+        // b is a linear function of a, 3 days later
+        let a = irregular(40);
+        let b: HashMap<i32, f64> = a.iter().map(|(day, val)| (day + 3, 2.0 * val + 1.0)).collect();
+        assert!((crosscorrelation(&a, &b, 3).unwrap() - 1.0).abs() < 1e-9);
+        assert!(crosscorrelation(&a, &b, 0).unwrap() < 0.9);
+        // Negated, the correlation is -1
+        let negated: HashMap<i32, f64> = b.iter().map(|(day, val)| (*day, -val)).collect();
+        assert!((crosscorrelation(&a, &negated, 3).unwrap() + 1.0).abs() < 1e-9);
+        // Too few days in common, or a constant series, give nothing
+        assert!(crosscorrelation(&a, &b, 41).is_none());
+        assert!(crosscorrelation(&a, &series(&[1.0; 40]), 0).is_none());
+    }
+
+    #[test]
+    fn bestlag_finds_delay() {
+        let a = irregular(60);
+        let b: HashMap<i32, f64> = a.iter().map(|(day, val)| (day + 7, 0.5 * val)).collect();
+        assert_eq!(bestlag(&a, &b, 14), Some(7));
+        // A lag beyond maxlag can't be found
+        assert_ne!(bestlag(&a, &b, 5), Some(7));
+        assert_eq!(bestlag(&a, &HashMap::new(), 14), None);
+    }
+
+    #[test]
+    fn leadlag_sign() {
+        // a leads b by 5 days
+        let a = irregular(60);
+        let b: HashMap<i32, f64> = a.iter().map(|(day, val)| (day + 5, 3.0 * val)).collect();
+        let (lag, r) = leadlag(&a, &b, 10).unwrap();
+        assert_eq!(lag, 5);
+        assert!((r - 1.0).abs() < 1e-9);
+        // So b lags a
+        let (lag, r) = leadlag(&b, &a, 10).unwrap();
+        assert_eq!(lag, -5);
+        assert!((r - 1.0).abs() < 1e-9);
+        assert_eq!(leadlag(&a, &HashMap::new(), 10), None);
+    }
+
+    #[test]
+    fn leadlag_ties() {
+        // With a period of 4 and whole periods in common at each lag, the correlations at
+        // equally good lags come out exactly equal
+        let period = |days: usize, offset: usize| {
+            series(&(0..days).map(|i| [0.0, 2.0, 4.0, 2.0][(i + offset) % 4]).collect::<Vec<_>>())
+        };
+        // Identical at lags 0 and 4 either way; the shortest wins
+        let a = period(104, 0);
+        assert_eq!(leadlag(&a, &a, 4), Some((0, 1.0)));
+        // Half a period apart, b is as much 2 days behind a as 2 days ahead; the negative
+        // lag wins
+        let (a, b) = (period(102, 0), period(102, 2));
+        assert_eq!(crosscorrelation(&a, &b, 2), crosscorrelation(&a, &b, -2));
+        assert_eq!(leadlag(&a, &b, 3), Some((-2, 1.0)));
+    }
+
+    #[test]
+    fn laggedcfr_windows() {
+        // 100 cases a day from day 100, and 2 deaths a day from day 105, so 2% of cases
+        // die 5 days later
+        let cases = series(&[100.0; 20]);
+        let deaths: HashMap<i32, f64> = (105..120).map(|day| (day, 2.0)).collect();
+        let expected: Vec<(i32, f64)> = (107..120).map(|day| (day, 2.0)).collect();
+        // Days without a full window of deaths, or of cases lag days before, are left out
+        assert_close(&laggedcfr(&cases, &deaths, 5, 3), &expected);
+        assert_close(&laggedcfr(&cases, &deaths, 0, 3), &expected);
+        // With a longer lag, the case windows are what's missing on the earlier days
+        let expected: Vec<(i32, f64)> = (112..120).map(|day| (day, 2.0)).collect();
+        assert_close(&laggedcfr(&cases, &deaths, 10, 3), &expected);
+        // No cases, no ratio
+        assert!(laggedcfr(&series(&[0.0; 20]), &deaths, 5, 3).is_empty());
+    }
+
+    #[test]
+    fn poissoninterval_bounds() {
+        // With a population of 100k, rates are counts.  The exact 95% interval for 10 is
+        // 4.795 to 18.39, and for 0 is 0 to 3.689.
+        let counts = series(&[10.0, 10.0, 10.0, 0.0]);
+        let sums = series(&[10.0, 20.0, 30.0, 20.0]);
+        let interval = poissoninterval(&sums, &counts, 100000.0, 3, false, Partial::Shrink);
+        assert_eq!(interval.center, sums);
+        assert!((interval.lower[&100] - 4.795).abs() < 0.05 && (interval.upper[&100] - 18.39).abs() < 0.05);
+        assert!(interval.lower[&102] < 30.0 && 30.0 < interval.upper[&102]);
+        assert!(interval.lower[&102] > interval.lower[&101]);
+
+        let zeros = series(&[0.0]);
+        let interval = poissoninterval(&zeros, &zeros, 100000.0, 3, false, Partial::Shrink);
+        assert_eq!(interval.lower[&100], 0.0);
+        assert!((interval.upper[&100] - 3.689).abs() < 0.05);
+    }
+
+    #[test]
+    fn poissoninterval_partial_means() {
+        // On the first day, a shrunk 3-day mean is the one day's count, while one with
+        // zeros filled in is a third of it; each interval should go with its mean
+        let counts = series(&[30.0, 30.0, 30.0]);
+        let shrunk = poissoninterval(&series(&[30.0, 30.0, 30.0]), &counts, 100000.0, 3, true, Partial::Shrink);
+        let zero = poissoninterval(&series(&[10.0, 20.0, 30.0]), &counts, 100000.0, 3, true, Partial::Zero);
+        assert!(shrunk.lower[&100] < 30.0 && 30.0 < shrunk.upper[&100]);
+        assert!(zero.lower[&100] < 10.0 && 10.0 < zero.upper[&100]);
+        assert!(zero.lower[&101] < 20.0 && 20.0 < zero.upper[&101]);
+        assert!((zero.upper[&100] * 3.0 - shrunk.upper[&100]).abs() < 1e-9);
+        // Once the window is full, the two agree
+        assert_eq!(zero.lower[&102], shrunk.lower[&102]);
+        assert_eq!(zero.upper[&102], shrunk.upper[&102]);
+    }
+
+    #[test]
+    fn ebshrink_known_variance() {
+        // Rates 0.2 and 0.6 on 100 each: mean 0.4, variance 0.04, of which 0.4 / 100 is
+        // expected from chance, leaving 0.036 between the regions.  Each rate keeps
+        // 0.036 / (0.036 + 0.004) = 0.9 of its distance from the mean.
+        let regions: HashMap<&str, (f64, f64)> =
+            vec![("a", (20.0, 100.0)), ("b", (60.0, 100.0)), ("none", (0.0, 0.0))].into_iter().collect();
+        let shrunk = ebshrink(&regions);
+        assert_eq!(shrunk.len(), 2);
+        assert!((shrunk["a"] - 0.22).abs() < 1e-9, "{:?}", shrunk);
+        assert!((shrunk["b"] - 0.58).abs() < 1e-9, "{:?}", shrunk);
+    }
+
+    #[test]
+    fn ebshrink_clamped_variance() {
+        // Rates 0.40 and 0.42 vary less than chance alone would make them, so the variance
+        // between them is estimated as 0 and both are shrunk all the way to the mean
+        let regions: HashMap<&str, (f64, f64)> = vec![("a", (40.0, 100.0)), ("b", (42.0, 100.0))].into_iter().collect();
+        let shrunk = ebshrink(&regions);
+        assert!((shrunk["a"] - 0.41).abs() < 1e-9, "{:?}", shrunk);
+        assert!((shrunk["b"] - 0.41).abs() < 1e-9, "{:?}", shrunk);
+        assert!(ebshrink::<&str>(&HashMap::new()).is_empty());
+    }
+
+    #[test]
+    fn cadence_changes() {
+        // Starting on a Saturday, Monday, Wednesday and Friday reports for eight weeks,
+        // then daily ones
+        let saturday = (100..107).find(|day| weekday(*day) == 5).unwrap();
+        let monday = saturday + 2;
+        let hm: HashMap<i32, f64> = (saturday..monday + 112)
+            .map(|day| match day < monday + 54 {
+                true => (day, [3.0, 0.0, 2.0, 0.0, 2.0, 0.0, 0.0][weekday(day)]),
+                false => (day, 1.0),
+            })
+            .collect();
+        let cadence = detectcadence(&hm);
+        assert_eq!(cadence[&(monday + 28)], [true, false, true, false, true, false, false]);
+        assert_eq!(cadence[&(monday + 84)], [true; 7]);
+
+        // Batches are spread back while reports are batched, and left alone after
+        let redistributed = redistribute(&hm, &cadence);
+        let expected: Vec<(i32, f64)> = (saturday..monday + 112).map(|day| (day, 1.0)).collect();
+        assert_close(&redistributed, &expected);
+    }
+
+    #[test]
+    fn sparse_daily_unchanged() {
+        // Low daily counts, drawn from a Poisson distribution with a fixed seed, have plenty
+        // of zero days, but not on a schedule
+        let mut state: u64 = 12345;
+        let mut uniform = || {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            ((state >> 11) as f64) / ((1u64 << 53) as f64)
+        };
+        for mean in &[0.3f64, 1.0, 2.0] {
+            let hm: HashMap<i32, f64> = (100..465)
+                .map(|day| {
+                    // Knuth's method: count uniform draws until their product drops below e^-mean
+                    let (mut count, mut product) = (0, uniform());
+                    while product > (-mean).exp() {
+                        count += 1;
+                        product *= uniform();
+                    }
+                    (day, count as f64)
+                })
+                .collect();
+            assert!(hm.values().filter(|val| **val == 0.0).count() > 30, "mean {}", mean);
+            assert!(detectcadence(&hm).values().all(|cadence| *cadence == [true; 7]), "mean {}", mean);
+            assert_eq!(debatch(&hm), hm, "mean {}", mean);
+        }
+    }
+}
@@ -0,0 +1,164 @@
+/* Build cache
+
+Copyright (c) 2020 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+ */
+
+use crate::charts::{ChartConfig, Output};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+/// Tracks which charts were generated from which inputs, so that a run against an
+/// unchanged database can skip the queries and re-use the fragments already on disk.
+pub struct BuildCache {
+    path: String,
+    /// Chart filename to the fingerprint it was last generated with
+    entries: HashMap<String, String>,
+    /// Fingerprint of the database file and the code that produced the charts
+    basefingerprint: String,
+    /// If set, nothing is ever considered fresh
+    force: bool,
+}
+
+/// Fingerprint the database file by its size and modification time.  Reading a
+/// database of this size on every run would cost more than the cache saves.
+fn fingerprint_db(dbfile: &str) -> String {
+    let meta = fs::metadata(dbfile).unwrap();
+    let mtime = meta
+        .modified()
+        .unwrap()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    format!("{}:{}", meta.len(), mtime)
+}
+
+/// Fingerprint the code version.  The package version alone is not bumped for every
+/// change, so the build script hashes the sources as well.
+fn fingerprint_code() -> String {
+    format!("{}:{}", env!("CARGO_PKG_VERSION"), env!("COVID19KS_SOURCE_HASH"))
+}
+
+impl BuildCache {
+    /// Load the cache from path, if it exists.  A missing or unreadable cache file
+    /// simply means everything will be regenerated.
+    pub fn load(path: &str, dbfile: &str, force: bool) -> BuildCache {
+        let entries = fs::read_to_string(path)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| {
+                let mut parts = line.splitn(2, '\t');
+                match (parts.next(), parts.next()) {
+                    (Some(chart), Some(fp)) => Some((String::from(chart), String::from(fp))),
+                    _ => None,
+                }
+            })
+            .collect();
+        BuildCache {
+            path: String::from(path),
+            entries,
+            basefingerprint: format!("{}/{}", fingerprint_db(dbfile), fingerprint_code()),
+            force,
+        }
+    }
+
+    /// Compute the fingerprint for a chart given its definition and configuration.
+    fn fingerprint(&self, chart: &str, definition: &str, config: &ChartConfig) -> String {
+        let mut hasher = DefaultHasher::new();
+        self.basefingerprint.hash(&mut hasher);
+        chart.hash(&mut hasher);
+        definition.hash(&mut hasher);
+        config.describe(chart).hash(&mut hasher);
+        format!("{:016x}", hasher.finish())
+    }
+
+    /// True if every one of the charts was generated from the same inputs and
+    /// its output files are still present.
+    pub fn is_fresh(&self, charts: &[&str], definition: &str, config: &ChartConfig) -> bool {
+        !self.force
+            && charts.iter().all(|chart| {
+                self.entries.get(*chart) == Some(&self.fingerprint(chart, definition, config))
+                    && Path::new(&format!("html-fragments/{}.html", chart)).exists()
+                    && Path::new(&format!("html-entire/{}.html", chart)).exists()
+            })
+    }
+
+    /// If the charts are fresh, append their existing fragments to the combined HTML, just
+    /// as charts::write_generic would have, and return true.  Otherwise, return false and
+    /// leave it to the caller to regenerate them.
+    pub fn reuse(&self, charts: &[&str], definition: &str, out: &mut Output) -> bool {
+        if !self.is_fresh(charts, definition, &out.config) {
+            return false;
+        }
+        for chart in charts {
+            println!("Reusing unchanged {}", chart);
+            let inlinestr = fs::read(format!("html-fragments/{}.html", chart)).unwrap();
+            out.append(chart, &inlinestr);
+        }
+        true
+    }
+
+    /// Note that the charts have been generated from the given definition.
+    pub fn record(&mut self, charts: &[&str], definition: &str, config: &ChartConfig) {
+        for chart in charts {
+            let fp = self.fingerprint(chart, definition, config);
+            self.entries.insert(String::from(*chart), fp);
+        }
+    }
+
+    /// Write the cache back to disk.
+    pub fn save(&self) {
+        let mut keys: Vec<&String> = self.entries.keys().collect();
+        keys.sort();
+        let mut file = File::create(&self.path).unwrap();
+        for key in keys {
+            writeln!(file, "{}\t{}", key, self.entries.get(key).unwrap()).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chart_last_date;
+
+    #[test]
+    fn unchanged_db_fresh_on_later_day() {
+        // Any file will do for the database, so long as it doesn't change during the test
+        let mut cache = BuildCache::load("/nonexistent/.buildcache", "Cargo.toml", false);
+        let config = ChartConfig::default();
+        let definition = |today, db_last_date| format!("incidence_100k 100 {}", chart_last_date(today, Some(db_last_date)));
+
+        // A database ending on day 500, run on day 510 and again the next night
+        cache.record(&["incidence"], &definition(510, 500), &config);
+        assert_eq!(cache.entries["incidence"], cache.fingerprint("incidence", &definition(511, 500), &config));
+        // Once it has a new day in it, the chart is regenerated
+        assert_ne!(cache.entries["incidence"], cache.fingerprint("incidence", &definition(511, 501), &config));
+    }
+
+    #[test]
+    fn chart_last_date_clamped() {
+        assert_eq!(chart_last_date(510, Some(500)), 500);
+        assert_eq!(chart_last_date(510, Some(509)), 508);
+        assert_eq!(chart_last_date(510, None), 508);
+    }
+}
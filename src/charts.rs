@@ -17,30 +17,288 @@ Copyright (c) 2020 John Goerzen
 
  */
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Display;
+use std::fs;
 use std::fs::File;
 use std::io::Write;
-use serde::Serialize;
+use std::path::Path;
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
 
 use covid19db::dateutil::*;
 
+use crate::analysis::{largestkey, Interval};
+use crate::page;
+use crate::smoothing::Smoothing;
+
 // use itertools_num::linspace;
-use plotly::common::{Line, LineShape, Mode, Title,
+use plotly::common::{Anchor, ColorBar, ColorScale, ColorScalePalette, DashType, Fill, Line, LineShape, Mode,
+    Orientation, Title,
     // ColorScale, ColorScalePalette, DashType, Fill, Font, Line, LineShape, Marker, Mode, Title,
 };
 // use plotly::layout::{Axis, BarMode, Layout, Legend, TicksDirection};
-use plotly::layout::{Axis, Layout};
+use plotly::layout::{Annotation, Axis, AxisType, Layout, Legend};
 // use plotly::plot::ImageFormat;
 // use plotly::{Bar, NamedColor, Plot, Rgb, Rgba, Scatter};
-use plotly::{Plot, Scatter};
+use plotly::{HeatMap, Plot, Rgb, Rgba, Scatter};
 // use rand_distr::{Distribution, Normal, Uniform};
 
-pub fn hmtoseries<U: Clone>(hm: &HashMap<i32, U>, keylist: impl Iterator<Item = i32>) -> (Vec<i32>, Vec<U>) {
+/// How the lines between points are drawn
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Shape {
+    Linear,
+    Spline,
+}
+
+/// Which rate charts of counties show
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Rate {
+    /// The county's own rate
+    Observed,
+    /// The empirical Bayes estimate, shrunk toward the statewide rate the less the
+    /// county's own cases tell us
+    Shrunk,
+}
+
+/// Where the legend goes
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LegendPlacement {
+    Right,
+    Top,
+    Bottom,
+    Hidden,
+}
+
+/// Per-chart display options.  These are set in code and may be overridden for any chart
+/// by a section named for the chart in charts.toml, for instance:
+///
+/// ```toml
+/// [global-100k]
+/// y_log = true
+/// legend = "bottom"
+/// x_range = ["2020-07-01", "2020-12-31"]
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ChartOptions {
+    pub y_log: bool,
+    pub y_range: Option<(f64, f64)>,
+    pub x_range: Option<(NaiveDate, NaiveDate)>,
+    pub shape: Shape,
+    pub markers: bool,
+    pub legend: LegendPlacement,
+    /// Shown in smaller type beneath the title, such as a note on the data's sources
+    pub subtitle: Option<String>,
+    /// Shown after the subtitle.  This is for notes the code adds once the configuration
+    /// is applied, such as on how the configured options affect the chart, so that a
+    /// subtitle set in charts.toml doesn't replace them.
+    pub note: Option<String>,
+    /// How the data is smoothed before it is charted.  Chart writers don't apply this
+    /// themselves; it is up to the code preparing the data.
+    pub smoothing: Smoothing,
+    /// Which rate to show, for charts of counties that support the choice
+    pub rate: Rate,
+    /// The regions shown, for charts that let them be chosen
+    pub regions: Vec<String>,
+    /// Give every series its own line pattern as well as its own color, for printing in gray
+    pub dashes: bool,
+}
+
+impl Default for ChartOptions {
+    fn default() -> ChartOptions {
+        ChartOptions {
+            y_log: false,
+            y_range: None,
+            x_range: None,
+            shape: Shape::Spline,
+            markers: false,
+            legend: LegendPlacement::Right,
+            subtitle: None,
+            note: None,
+            smoothing: Smoothing::None,
+            rate: Rate::Observed,
+            regions: Vec::new(),
+            dashes: false,
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl ChartOptions {
+    pub fn new() -> ChartOptions {
+        Default::default()
+    }
+
+    pub fn y_log(mut self, y_log: bool) -> ChartOptions {
+        self.y_log = y_log;
+        self
+    }
+
+    /// Range of the y axis, in data units even when y_log is set
+    pub fn y_range(mut self, low: f64, high: f64) -> ChartOptions {
+        self.y_range = Some((low, high));
+        self
+    }
+
+    /// Range of dates shown.  This applies only to charts with dates on the x axis.
+    pub fn x_range(mut self, firstdate: i32, lastdate: i32) -> ChartOptions {
+        self.x_range = Some((day_to_nd(firstdate), day_to_nd(lastdate)));
+        self
+    }
+
+    pub fn shape(mut self, shape: Shape) -> ChartOptions {
+        self.shape = shape;
+        self
+    }
+
+    pub fn markers(mut self, markers: bool) -> ChartOptions {
+        self.markers = markers;
+        self
+    }
+
+    pub fn legend(mut self, legend: LegendPlacement) -> ChartOptions {
+        self.legend = legend;
+        self
+    }
+
+    pub fn subtitle(mut self, subtitle: Option<String>) -> ChartOptions {
+        self.subtitle = subtitle;
+        self
+    }
+
+    pub fn note(mut self, note: Option<String>) -> ChartOptions {
+        self.note = note;
+        self
+    }
+
+    pub fn smoothing(mut self, smoothing: Smoothing) -> ChartOptions {
+        self.smoothing = smoothing;
+        self
+    }
+
+    pub fn rate(mut self, rate: Rate) -> ChartOptions {
+        self.rate = rate;
+        self
+    }
+
+    pub fn regions(mut self, regions: Vec<&str>) -> ChartOptions {
+        self.regions = regions.into_iter().map(String::from).collect();
+        self
+    }
+
+    pub fn dashes(mut self, dashes: bool) -> ChartOptions {
+        self.dashes = dashes;
+        self
+    }
+}
+
+/// The section of charts.toml whose options apply to every chart
+const DEFAULT_SECTION: &str = "default";
+
+/// Overrides for chart options, read from charts.toml
+#[derive(Debug, Default)]
+pub struct ChartConfig {
+    charts: toml::value::Table,
+    /// The charts that take the smoothing option
+    smoothed: &'static [&'static str],
+}
+
+impl ChartConfig {
+    /// Load the configuration.  If the file does not exist, nothing is overridden.  Only the
+    /// smoothed charts take a smoothing option; giving one for any other chart is an error,
+    /// and one in [default] applies only to the smoothed charts.
+    pub fn load(path: &str, smoothed: &'static [&'static str]) -> ChartConfig {
+        if !Path::new(path).exists() {
+            return ChartConfig { smoothed, ..Default::default() };
+        }
+        let charts = fs::read_to_string(path)
+            .unwrap()
+            .parse::<toml::Value>()
+            .unwrap_or_else(|e| panic!("Error parsing {}: {}", path, e));
+        let charts = match charts {
+            toml::Value::Table(charts) => charts,
+            _ => panic!("{} is not a table", path),
+        };
+        // Catch bad smoothing options now, rather than partway through a run
+        for (section, options) in &charts {
+            if let Some(smoothing) = options.get("smoothing") {
+                if section != DEFAULT_SECTION && !smoothed.contains(&section.as_str()) {
+                    panic!("Error in {} [{}]: this chart doesn't take a smoothing option", path, section);
+                }
+                smoothing
+                    .clone()
+                    .try_into::<Smoothing>()
+                    .map_err(|e| e.to_string())
+                    .and_then(|smoothing| smoothing.validate())
+                    .unwrap_or_else(|e| panic!("Error in {} [{}] smoothing: {}", path, section, e));
+            }
+        }
+        ChartConfig { charts, smoothed }
+    }
+
+    /// Apply any overrides for the given chart to the options given in code.  Those in a
+    /// [default] section apply to every chart, unless the chart's own section says otherwise.
+    pub fn apply(&self, filename: &str, opts: &ChartOptions) -> ChartOptions {
+        let sections: Vec<&toml::Value> =
+            [DEFAULT_SECTION, filename].iter().filter_map(|x| self.charts.get(*x)).collect();
+        if sections.is_empty() {
+            return opts.clone();
+        }
+        let mut merged = toml::Value::try_from(opts).unwrap();
+        for overrides in sections {
+            if let (toml::Value::Table(merged), toml::Value::Table(overrides)) = (&mut merged, overrides) {
+                for (k, v) in overrides {
+                    if k == "smoothing" && !self.smoothed.contains(&filename) {
+                        continue;
+                    }
+                    merged.insert(k.clone(), v.clone());
+                }
+            }
+        }
+        merged
+            .try_into()
+            .unwrap_or_else(|e| panic!("Bad options for {}: {}", filename, e))
+    }
+
+    /// A description of the overrides for the given chart, for the build cache
+    pub fn describe(&self, filename: &str) -> String {
+        [DEFAULT_SECTION, filename]
+            .iter()
+            .map(|x| self.charts.get(*x).map(|x| x.to_string()).unwrap_or_default())
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+/// Where charts are written: the combined HTML file, along with the configuration that
+/// applies to each chart and the charts written so far.
+pub struct Output {
+    pub bightml: File,
+    pub config: ChartConfig,
+    pub written: Vec<String>,
+}
+
+impl Output {
+    /// Add a chart's fragment to the combined HTML file, and note that it was written.
+    pub fn append(&mut self, filename: &str, html: &[u8]) {
+        assert!(page::registered(filename), "{} has no place in page::SECTIONS; add it there with a description", filename);
+        self.bightml.write_all(html).unwrap();
+        self.bightml.write_all(b"<br/>\n").unwrap();
+        self.written.push(String::from(filename));
+    }
+}
+
+/// Pull the keys in keylist out of hm, in order.  offset is subtracted from each returned key,
+/// so that, for instance, passing the day a series started gives days since its start.
+pub fn hmtoseries<U: Clone>(hm: &HashMap<i32, U>, keylist: impl Iterator<Item = i32>, offset: i32) -> (Vec<i32>, Vec<U>) {
     let mut retkeys = Vec::new();
     let mut retvals = Vec::new();
     for key in keylist {
         if let Some(val) = hm.get(&key) {
-            retkeys.push(key);
+            retkeys.push(key - offset);
             retvals.push(val.clone());
         }
     }
@@ -48,64 +306,795 @@ pub fn hmtoseries<U: Clone>(hm: &HashMap<i32, U>, keylist: impl Iterator<Item =
 }
 
 
-pub fn write_generic<Y: Serialize + Clone + 'static>(
+#[allow(clippy::too_many_arguments)]
+pub fn write_generic<Y: Serialize + Clone + Into<f64> + 'static>(
     filename: &'static str, // grumble due to plotly library
-    bightml: &mut File,
+    out: &mut Output,
     title: &str,
     yaxis: &str,
     series: Vec<(&str, &HashMap<i32, Y>)>,
     firstdate: i32,
     lastdate: i32,
+    opts: &ChartOptions,
 ) {
-    let mut plot = Plot::new();
+    let opts = out.config.apply(filename, opts);
+    let traces = series
+        .into_iter()
+        .enumerate()
+        .map(|(group, (label, data))| {
+            let (keys, vals) = hmtoseries(data, firstdate..=lastdate, 0);
+            Trace::new(label, group, keys.into_iter().map(day_to_nd).collect(), vals)
+        })
+        .collect();
+    write_plot(filename, out, title, date_axis(&opts), "Date", yaxis, traces, vec![], &opts);
+}
 
-    // See https://plotly.com/python/reference/scatter/#scatter-line-smoothing for smoothing
-    // this line is unnecessary when not using it.
-    let line = Line::new().shape(LineShape::Spline).smoothing(1.0);
-    for (label, data) in series {
-        let (keys, vals) = hmtoseries(data, firstdate..=lastdate);
-        let trace = Scatter::new(
-            keys.into_iter().map(day_to_nd),
-            vals
+/// A band for the interval over the days in keys
+fn interval_band<'a>(label: &'a str, group: usize, interval: &Interval, keys: impl Iterator<Item = i32>) -> Band<'a, NaiveDate> {
+    let (x, lower) = hmtoseries(&interval.lower, keys, 0);
+    let (_, upper) = hmtoseries(&interval.upper, x.iter().copied(), 0);
+    Band { label, group, x: x.into_iter().map(day_to_nd).collect(), lower, upper }
+}
+
+/// Like write_generic, but each series may be given with an interval, such as a confidence
+/// interval, which is shaded around it.
+#[allow(clippy::too_many_arguments)]
+pub fn write_banded(
+    filename: &'static str, // grumble due to plotly library
+    out: &mut Output,
+    title: &str,
+    yaxis: &str,
+    series: Vec<(&str, &HashMap<i32, f64>, Option<&Interval>)>,
+    firstdate: i32,
+    lastdate: i32,
+    opts: &ChartOptions,
+) {
+    write_forecasts(
+        filename,
+        out,
+        title,
+        yaxis,
+        series.into_iter().map(|(label, data, band)| (label, data, band, None)).collect(),
+        firstdate,
+        lastdate,
+        opts,
+    )
+}
+
+/// Like write_banded, but each series may also be given with a forecast, which is drawn as
+/// a dashed continuation of the series with its prediction interval shaded.  Forecasts are
+/// drawn in full, even past lastdate.
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+pub fn write_forecasts(
+    filename: &'static str, // grumble due to plotly library
+    out: &mut Output,
+    title: &str,
+    yaxis: &str,
+    series: Vec<(&str, &HashMap<i32, f64>, Option<&Interval>, Option<&Interval>)>,
+    firstdate: i32,
+    lastdate: i32,
+    opts: &ChartOptions,
+) {
+    let opts = out.config.apply(filename, opts);
+    let mut traces = Vec::new();
+    let mut bands = Vec::new();
+    for (group, (label, data, band, forecast)) in series.into_iter().enumerate() {
+        let (keys, vals) = hmtoseries(data, firstdate..=lastdate, 0);
+        if let Some(band) = band {
+            bands.push(interval_band(label, group, band, keys.iter().copied()));
+        }
+        traces.push(Trace::new(label, group, keys.into_iter().map(day_to_nd).collect(), vals));
+        if let Some(forecast) = forecast {
+            let last = *largestkey(&forecast.center).unwrap();
+            let (keys, vals) = hmtoseries(&forecast.center, firstdate..=last, 0);
+            let (_, lower) = hmtoseries(&forecast.lower, keys.iter().copied(), 0);
+            let (_, upper) = hmtoseries(&forecast.upper, keys.iter().copied(), 0);
+            let keys: Vec<NaiveDate> = keys.into_iter().map(day_to_nd).collect();
+            let mut trace = Trace::new(label, group, keys.clone(), vals);
+            trace.dash = Some(DashType::Dash);
+            trace.legend = false;
+            traces.push(trace);
+            bands.push(Band { label, group, x: keys, lower, upper });
+        }
+    }
+    write_plot(filename, out, title, date_axis(&opts), "Date", yaxis, traces, bands, &opts);
+}
+
+/// Plot each series over the same list of categories, such as days of the week, instead
+/// of dates.
+pub fn write_categorical(
+    filename: &'static str, // grumble due to plotly library
+    out: &mut Output,
+    title: &str,
+    yaxis: &str,
+    categories: &[&str],
+    series: Vec<(&str, Vec<f64>)>,
+    opts: &ChartOptions,
+) {
+    let opts = out.config.apply(filename, opts);
+    let categories: Vec<String> = categories.iter().map(|x| String::from(*x)).collect();
+    let traces = series
+        .into_iter()
+        .enumerate()
+        .map(|(group, (label, vals))| Trace::new(label, group, categories.clone(), vals))
+        .collect();
+    write_plot(filename, out, title, Axis::new(), "Category", yaxis, traces, vec![], &opts);
+}
+
+/// Escape text for inclusion in HTML
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Write a table of text, rather than a chart, as a page and a fragment of its own and
+/// into the combined HTML.
+pub fn write_table(filename: &str, out: &mut Output, caption: &str, headers: &[&str], rows: Vec<Vec<String>>) {
+    let mut html = format!("<table id=\"{}\">\n<caption>{}</caption>\n<tr>", filename, escape_html(caption));
+    for header in headers {
+        html.push_str(&format!("<th scope=\"col\">{}</th>", escape_html(header)));
+    }
+    html.push_str("</tr>\n");
+    for row in rows {
+        html.push_str("<tr>");
+        for cell in row {
+            html.push_str(&format!("<td>{}</td>", escape_html(&cell)));
+        }
+        html.push_str("</tr>\n");
+    }
+    html.push_str("</table>\n");
+    println!("Writing to {}", filename);
+    File::create(format!("html-entire/{}.html", filename))
+        .unwrap()
+        .write_all(
+            format!(
+                "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n<body>\n{}</body>\n</html>\n",
+                escape_html(caption),
+                html
+            )
+            .as_ref(),
         )
-        .mode(Mode::Lines)
-        .line(line.clone()) // only needed for smoothing
-        .name(label);
-        plot.add_trace(trace);
+        .unwrap();
+    File::create(format!("html-fragments/{}.html", filename))
+        .unwrap()
+        .write_all(html.as_ref())
+        .unwrap();
+    out.append(filename, html.as_ref());
+}
+
+/// The x axis for charts with dates on it
+fn date_axis(opts: &ChartOptions) -> Axis {
+    match opts.x_range {
+        Some((low, high)) => Axis::new().range(vec![low.to_string(), high.to_string()]),
+        None => Axis::new(),
     }
+}
+
+/// Like write_generic, but each series is given with its own start day, and the x axis is
+/// days since that start rather than calendar date.  This lines up series that started at
+/// different times, such as outbreaks or policies in different counties.  Each series is
+/// plotted from lead days before its start through lastdate.
+#[allow(clippy::too_many_arguments)]
+pub fn write_aligned<Y: Serialize + Clone + Into<f64> + 'static>(
+    filename: &'static str, // grumble due to plotly library
+    out: &mut Output,
+    title: &str,
+    xaxis: &str,
+    yaxis: &str,
+    series: Vec<(&str, &HashMap<i32, Y>, i32)>,
+    lead: i32,
+    lastdate: i32,
+    opts: &ChartOptions,
+) {
+    let opts = out.config.apply(filename, opts);
+    let traces = series
+        .into_iter()
+        .enumerate()
+        .map(|(group, (label, data, start))| {
+            let (keys, vals) = hmtoseries(data, (start - lead)..=lastdate, start);
+            Trace::new(label, group, keys, vals)
+        })
+        .collect();
+    write_plot(filename, out, title, Axis::new().title(Title::new(xaxis)), xaxis, yaxis, traces, vec![], &opts);
+}
 
+/// Like write_aligned, but for models fit around the start of each series: each is given
+/// as (label, observed, fitted, counterfactual, start), and the fitted values are drawn
+/// dashed and the counterfactual dotted, in the color of the observed values.
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+pub fn write_fitted(
+    filename: &'static str, // grumble due to plotly library
+    out: &mut Output,
+    title: &str,
+    xaxis: &str,
+    yaxis: &str,
+    series: Vec<(&str, &HashMap<i32, f64>, &HashMap<i32, f64>, &HashMap<i32, f64>, i32)>,
+    lead: i32,
+    lastdate: i32,
+    opts: &ChartOptions,
+) {
+    let opts = out.config.apply(filename, opts);
+    let mut traces = Vec::new();
+    for (group, (label, observed, fitted, counterfactual, start)) in series.into_iter().enumerate() {
+        let (keys, vals) = hmtoseries(observed, (start - lead)..=lastdate, start);
+        traces.push(Trace::new(label, group, keys, vals));
+        for (data, dash) in &[(fitted, DashType::Dash), (counterfactual, DashType::Dot)] {
+            let (keys, vals) = hmtoseries(data, (start - lead)..=lastdate, start);
+            let mut trace = Trace::new(label, group, keys, vals);
+            trace.dash = Some(dash.clone());
+            trace.legend = false;
+            traces.push(trace);
+        }
+    }
+    write_plot(filename, out, title, Axis::new().title(Title::new(xaxis)), xaxis, yaxis, traces, vec![], &opts);
+}
+
+/// The title with the subtitle and note, if any, beneath it
+fn fulltitle(title: &str, opts: &ChartOptions) -> String {
+    let sub: Vec<&str> = opts.subtitle.iter().chain(opts.note.iter()).map(|x| x.as_str()).collect();
+    match sub.is_empty() {
+        true => String::from(title),
+        false => format!("{}<br><sub>{}</sub>", title, sub.join("; ")),
+    }
+}
+
+/// Write a heatmap with a row and a column for each label, the first at the top left,
+/// colored by z from red through white to blue, with text over each cell.  cells are
+/// given by row, and missing ones are left blank.
+#[allow(clippy::too_many_arguments)]
+pub fn write_heatmap(
+    filename: &'static str, // grumble due to plotly library
+    out: &mut Output,
+    title: &str,
+    xaxis: &str,
+    yaxis: &str,
+    zaxis: &str,
+    labels: &[&str],
+    cells: Vec<Vec<Option<(f64, String)>>>,
+    opts: &ChartOptions,
+) {
+    let opts = out.config.apply(filename, opts);
+    let mut plot = Plot::new();
+    // Plotly draws the first row at the bottom, so reverse them to read from the top
+    let collabels: Vec<String> = labels.iter().map(|l| String::from(*l)).collect();
+    let rowlabels: Vec<String> = collabels.iter().rev().cloned().collect();
+    let z: Vec<Vec<Option<f64>>> =
+        cells.iter().rev().map(|row| row.iter().map(|cell| cell.as_ref().map(|(z, _)| *z)).collect()).collect();
+    plot.add_trace(
+        HeatMap::new(collabels, rowlabels, z)
+            .color_scale(ColorScale::Palette(ColorScalePalette::RdBu))
+            .color_bar(ColorBar::new().title(Title::new(zaxis))),
+    );
+    // Cell text for the data table, without markup
+    let texts: Vec<Vec<String>> = cells
+        .iter()
+        .map(|row| {
+            row.iter()
+                .map(|cell| cell.as_ref().map(|(_, text)| text.replace("<br>", " ")).unwrap_or_default())
+                .collect()
+        })
+        .collect();
+    let mut annotations = Vec::new();
+    for (row, rowlabel) in cells.into_iter().zip(labels) {
+        for (cell, collabel) in row.into_iter().zip(labels) {
+            if let Some((_, text)) = cell {
+                annotations.push(Annotation::new().x(*collabel).y(*rowlabel).text(&text).show_arrow(false));
+            }
+        }
+    }
+    let title = fulltitle(title, &opts);
     let layout = Layout::new()
-        .title(Title::new(title))
-        .y_axis(Axis::new().title(Title::new(yaxis)));
+        .title(Title::new(&title))
+        .x_axis(Axis::new().title(Title::new(xaxis)))
+        .y_axis(Axis::new().title(Title::new(yaxis)))
+        .annotations(annotations);
+    plot.set_layout(layout);
+    let rows = labels
+        .iter()
+        .zip(&texts)
+        .map(|(label, row)| (String::from(*label), row.clone()))
+        .collect();
+    let description = format!(
+        "<p id=\"{}-summary\">{}, for each pair of the {} regions.</p>\n{}",
+        filename,
+        escape_html(zaxis),
+        labels.len(),
+        datatable(filename, zaxis, yaxis, labels, rows)
+    );
+    save_plot(filename, out, plot, &description);
+}
+
+/// A colorblind-safe palette (Okabe and Ito), with yellow last since it is hard to see
+/// on white.  We assign these ourselves, so that a series and anything drawn along with
+/// it, such as a forecast, get the same color.
+const PALETTE: [(u8, u8, u8); 8] = [
+    (0xe6, 0x9f, 0x00), // orange
+    (0x56, 0xb4, 0xe9), // sky blue
+    (0x00, 0x9e, 0x73), // bluish green
+    (0x00, 0x72, 0xb2), // blue
+    (0xd5, 0x5e, 0x00), // vermillion
+    (0xcc, 0x79, 0xa7), // reddish purple
+    (0x00, 0x00, 0x00), // black
+    (0xf0, 0xe4, 0x42), // yellow
+];
+
+/// Line patterns, for telling apart series that share a color, or all series when the
+/// dashes option is set.  Plain dashes are left for forecasts and fits, and dots for
+/// counterfactuals.
+const DASHES: [DashType; 3] = [DashType::Solid, DashType::DashDot, DashType::LongDashDot];
+
+/// The color and line pattern of style s, as indexes into PALETTE and DASHES.  The colors
+/// come around every PALETTE.len() styles, solid the first time and patterned after; with
+/// the dashes option, the pattern changes with every style instead.  Either way, the first
+/// PALETTE.len() * DASHES.len() styles all look different.
+fn styleparts(style: usize, dashes: bool) -> (usize, usize) {
+    match dashes {
+        true => (style % PALETTE.len(), style % DASHES.len()),
+        false => (style % PALETTE.len(), (style / PALETTE.len()) % DASHES.len()),
+    }
+}
+
+/// Regions that keep the same style on every chart, as an index into the styles.  Each has
+/// a style of its own, so that a region looks the same everywhere and like no other region
+/// here.  Kansas and its counties and groups get the solid colors; the states and
+/// countries they are compared with get patterned ones.  Yellow, being hard to see, is
+/// left for series of other regions, which get the first styles neither taken on their
+/// chart nor kept for a region here.
+const REGION_STYLES: &[(&str, usize)] = &[
+    ("Kansas", 0),
+    ("Sedgwick", 1),
+    ("Harvey", 2),
+    ("McPherson", 3),
+    ("Marion", 4),
+    ("Wichita metro", 5),
+    ("Meatpacking counties", 6),
+    ("Missouri", 9),
+    ("Nebraska", 10),
+    ("USA", 11),
+    ("Colorado", 12),
+    ("Oklahoma", 13),
+    ("Canada", 14),
+    ("United Kingdom", 17),
+    ("France", 18),
+    ("Taiwan", 19),
+];
+
+/// The region a series label names, for looking up its style: the label less anything in
+/// parentheses and a trailing " County", so that "Sedgwick County (NYT)" is Sedgwick.
+fn labelregion(label: &str) -> &str {
+    let base = label.split(" (").next().unwrap().trim();
+    base.strip_suffix(" County").unwrap_or(base)
+}
+
+/// The style for each group, given the label of each.  Regions in REGION_STYLES get
+/// theirs, unless the same region is on the chart twice; the rest get the first styles
+/// not taken on the chart or kept for a region in REGION_STYLES, in order.
+fn assignstyles(labels: &[(usize, &str)]) -> HashMap<usize, usize> {
+    let mut styles = HashMap::new();
+    let mut taken: HashSet<usize> = REGION_STYLES.iter().map(|(_, style)| *style).collect();
+    let mut used = HashSet::new();
+    for (group, label) in labels {
+        if let Some((_, style)) = REGION_STYLES.iter().find(|(region, _)| *region == labelregion(label)) {
+            if used.insert(*style) {
+                styles.insert(*group, *style);
+            }
+        }
+    }
+    for (group, _) in labels {
+        if !styles.contains_key(group) {
+            let style = (0..).find(|s| !taken.contains(s)).unwrap();
+            taken.insert(style);
+            styles.insert(*group, style);
+        }
+    }
+    styles
+}
+
+/// An x value on a chart, and the day it stands for, if any
+trait ChartX: Clone + Display + Ord {
+    fn day(&self) -> Option<i32>;
+}
+
+impl ChartX for NaiveDate {
+    fn day(&self) -> Option<i32> {
+        Some(nd_to_day(self))
+    }
+}
+
+/// Days relative to the start of an aligned series
+impl ChartX for i32 {
+    fn day(&self) -> Option<i32> {
+        Some(*self)
+    }
+}
+
+/// Categories, which aren't days
+impl ChartX for String {
+    fn day(&self) -> Option<i32> {
+        None
+    }
+}
+
+/// One line on a chart, with its x values already converted for plotting.  Traces with the
+/// same group are parts of the same series; they share a color and a legend entry.
+struct Trace<'a, X, Y> {
+    label: &'a str,
+    group: usize,
+    x: Vec<X>,
+    y: Vec<Y>,
+    dash: Option<DashType>,
+    legend: bool,
+}
+
+impl<'a, X, Y> Trace<'a, X, Y> {
+    fn new(label: &'a str, group: usize, x: Vec<X>, y: Vec<Y>) -> Trace<'a, X, Y> {
+        Trace { label, group, x, y, dash: None, legend: true }
+    }
+}
+
+/// A shaded band between lower and upper, drawn behind the traces of its group
+struct Band<'a, X> {
+    label: &'a str,
+    group: usize,
+    x: Vec<X>,
+    lower: Vec<f64>,
+    upper: Vec<f64>,
+}
+
+/// Plot the traces and bands, and write out the chart.
+#[allow(clippy::too_many_arguments)]
+fn write_plot<X, Y>(
+    filename: &'static str,
+    out: &mut Output,
+    title: &str,
+    x_axis: Axis,
+    xaxis: &str,
+    yaxis: &str,
+    traces: Vec<Trace<X, Y>>,
+    bands: Vec<Band<X>>,
+    opts: &ChartOptions,
+) where
+    X: Serialize + ChartX + 'static,
+    Y: Serialize + Clone + Into<f64> + 'static,
+{
+    let mut plot = Plot::new();
+    let description = describe_traces(filename, xaxis, yaxis, &traces);
+    let mut labels: Vec<(usize, &str)> = Vec::new();
+    for (group, label) in traces.iter().map(|t| (t.group, t.label)).chain(bands.iter().map(|b| (b.group, b.label))) {
+        if !labels.iter().any(|(g, _)| *g == group) {
+            labels.push((group, label));
+        }
+    }
+    let styles = assignstyles(&labels);
+    let color = |group: usize| PALETTE[styleparts(styles[&group], opts.dashes).0];
+    let dash = |group: usize| DASHES[styleparts(styles[&group], opts.dashes).1].clone();
+
+    // Bands go first, so that they are behind the lines.  Plotly fills from the upper
+    // trace down to the lower one just before it.
+    for band in bands {
+        let (r, g, b) = color(band.group);
+        let edge = Line::new().width(0.0).color(Rgb::new(r, g, b));
+        plot.add_trace(
+            Scatter::new(band.x.clone(), band.lower)
+                .mode(Mode::Lines)
+                .line(edge.clone())
+                .legend_group(band.label)
+                .show_legend(false)
+                .name(band.label),
+        );
+        plot.add_trace(
+            Scatter::new(band.x, band.upper)
+                .mode(Mode::Lines)
+                .line(edge)
+                .fill(Fill::ToNextY)
+                .fill_color(Rgba::new(r, g, b, 0.2))
+                .legend_group(band.label)
+                .show_legend(false)
+                .name(band.label),
+        );
+    }
+
+    // See https://plotly.com/python/reference/scatter/#scatter-line-smoothing for smoothing
+    let line = match opts.shape {
+        Shape::Spline => Line::new().shape(LineShape::Spline).smoothing(1.0),
+        Shape::Linear => Line::new().shape(LineShape::Linear),
+    };
+    let mode = if opts.markers { Mode::LinesMarkers } else { Mode::Lines };
+    for trace in traces {
+        let (r, g, b) = color(trace.group);
+        let group = trace.group;
+        let line = line.clone().color(Rgb::new(r, g, b)).dash(trace.dash.unwrap_or_else(|| dash(group)));
+        plot.add_trace(
+            Scatter::new(trace.x, trace.y)
+                .mode(mode.clone())
+                .line(line)
+                .legend_group(trace.label)
+                .show_legend(trace.legend)
+                .name(trace.label),
+        );
+    }
+
+    let mut y_axis = Axis::new().title(Title::new(yaxis));
+    if opts.y_log {
+        y_axis = y_axis.type_(AxisType::Log);
+    }
+    if let Some((low, high)) = opts.y_range {
+        // Plotly wants the range of a log axis as exponents
+        y_axis = match opts.y_log {
+            true => y_axis.range(vec![low.log10(), high.log10()]),
+            false => y_axis.range(vec![low, high]),
+        };
+    }
+    let title = fulltitle(title, opts);
+    let mut layout = Layout::new()
+        .title(Title::new(&title))
+        .x_axis(x_axis)
+        .y_axis(y_axis);
+    layout = match opts.legend {
+        LegendPlacement::Right => layout,
+        LegendPlacement::Hidden => layout.show_legend(false),
+        LegendPlacement::Top => layout.legend(
+            Legend::new().orientation(Orientation::Horizontal).x(0.0).y(1.0).y_anchor(Anchor::Bottom),
+        ),
+        LegendPlacement::Bottom => layout.legend(
+            Legend::new().orientation(Orientation::Horizontal).x(0.0).y(-0.15).y_anchor(Anchor::Top),
+        ),
+    };
     plot.set_layout(layout);
+    save_plot(filename, out, plot, &description);
+}
+
+/// Format a value for a summary or table
+fn formatvalue(val: f64) -> String {
+    format!("{:.2}", val)
+}
+
+/// A collapsible table of the given columns of values, one row per entry in rows
+fn datatable(filename: &str, caption: &str, rowheader: &str, columns: &[&str], rows: Vec<(String, Vec<String>)>) -> String {
+    let mut html = format!(
+        "<details>\n<summary>Data table</summary>\n<table id=\"{}-data\">\n<caption>{}</caption>\n<tr><th scope=\"col\">{}</th>",
+        filename,
+        escape_html(caption),
+        escape_html(rowheader)
+    );
+    for column in columns {
+        html.push_str(&format!("<th scope=\"col\">{}</th>", escape_html(column)));
+    }
+    html.push_str("</tr>\n");
+    for (header, cells) in rows {
+        html.push_str(&format!("<tr><th scope=\"row\">{}</th>", escape_html(&header)));
+        for cell in cells {
+            html.push_str(&format!("<td>{}</td>", escape_html(&cell)));
+        }
+        html.push_str("</tr>\n");
+    }
+    html.push_str("</table>\n</details>\n");
+    html
+}
+
+/// A text alternative to a chart: a summary of each series -- its latest value and its
+/// change over 7 and 14 days -- and a table of the values plotted.  Only the series in
+/// the legend are described, not forecasts or fits drawn along with them.
+fn describe_traces<X, Y>(filename: &str, xaxis: &str, yaxis: &str, traces: &[Trace<X, Y>]) -> String
+where
+    X: ChartX,
+    Y: Clone + Into<f64>,
+{
+    let traces: Vec<&Trace<X, Y>> = traces.iter().filter(|t| t.legend).collect();
+    let mut summary = Vec::new();
+    for trace in &traces {
+        let n = trace.y.len();
+        if n == 0 {
+            continue;
+        }
+        let val = |i: usize| -> f64 { trace.y[i].clone().into() };
+        let last = &trace.x[n - 1];
+        let mut text = format!("{}: {} at {}", trace.label, formatvalue(val(n - 1)), last);
+        // Compare against the value plotted that many days before the last, which may not
+        // be that many points back if the series has gaps.
+        if let Some(lastday) = last.day() {
+            for days in &[7, 14] {
+                if let Some(i) = trace.x.iter().position(|x| x.day() == Some(lastday - days)) {
+                    let change = val(n - 1) - val(i);
+                    let sign = if change >= 0.0 { "+" } else { "" };
+                    text.push_str(&format!(", {}{} over {} days", sign, formatvalue(change), days));
+                }
+            }
+        }
+        summary.push(text);
+    }
+
+    // A row for every x value in any series, in order, with a cell for each series
+    let mut table: BTreeMap<&X, Vec<String>> = BTreeMap::new();
+    for (column, trace) in traces.iter().enumerate() {
+        for (x, y) in trace.x.iter().zip(&trace.y) {
+            let cells = table.entry(x).or_insert_with(|| vec![String::new(); traces.len()]);
+            cells[column] = formatvalue(y.clone().into());
+        }
+    }
+    let rows = table.into_iter().map(|(x, cells)| (x.to_string(), cells)).collect();
+    let labels: Vec<&str> = traces.iter().map(|t| t.label).collect();
+    format!(
+        "<p id=\"{}-summary\">Latest values, {}: {}.</p>\n{}",
+        filename,
+        escape_html(yaxis),
+        escape_html(&summary.join("; ")),
+        datatable(filename, yaxis, xaxis, &labels, rows)
+    )
+}
+
+/// Write the finished plot as an entire page and as a fragment, and add it to the
+/// combined HTML file.  The fragment is a figure, with the plot described by description.
+fn save_plot(filename: &'static str, out: &mut Output, plot: Plot, description: &str) {
     println!("Writing to {}", filename);
     // plot.show();
     // plot.save(filename, ImageFormat::SVG, 1024, 768, 1.0);
     // plot.show_png(1024, 768);
     plot.to_html(format!("html-entire/{}.html", filename));
-    let inlinestr = plot.to_inline_html(Some(filename));
+    let inlinestr = format!(
+        "<figure aria-describedby=\"{}-summary\">\n{}\n<figcaption>\n{}</figcaption>\n</figure>\n",
+        filename,
+        plot.to_inline_html(Some(filename)),
+        description
+    );
     File::create(format!("html-fragments/{}.html", filename))
         .unwrap()
         .write_all(inlinestr.as_ref())
         .unwrap();
-    bightml.write_all(inlinestr.as_ref()).unwrap();
-    bightml.write_all(b"<br/>\n").unwrap();
+    out.append(filename, inlinestr.as_ref());
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn writecounties_100k(
     filename: &'static str, // grumble due to plotly
-    bightml: &mut File,
+    out: &mut Output,
     title: &str,
     yaxis: &str,
     counties: &Vec<&str>,
     bycounty: &HashMap<String, HashMap<i32, f64>>,
     firstdate: i32,
     lastdate: i32,
+    opts: &ChartOptions,
 ) {
     let series: Vec<(&str, &HashMap<i32, f64>)> = counties
         .iter()
         .map(|county| (*county, bycounty.get(&String::from(*county)).unwrap()))
         .collect();
 
-    write_generic(filename, bightml, title, yaxis, series, firstdate, lastdate)
+    write_generic(filename, out, title, yaxis, series, firstdate, lastdate, opts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smoothing::Partial;
+
+    #[test]
+    fn labelregion_strips_source_and_county() {
+        assert_eq!(labelregion("Sedgwick County (NYT)"), "Sedgwick");
+        assert_eq!(labelregion("Kansas (JHU)"), "Kansas");
+        assert_eq!(labelregion("Wichita metro"), "Wichita metro");
+    }
+
+    #[test]
+    fn assignstyles_keeps_region_styles() {
+        let styles = assignstyles(&[(0, "Sedgwick County (NYT)"), (1, "Kansas (NYT)"), (2, "Harvey County (NYT)")]);
+        assert_eq!(styles[&0], 1);
+        assert_eq!(styles[&1], 0);
+        assert_eq!(styles[&2], 2);
+    }
+
+    #[test]
+    fn region_styles_never_clash() {
+        // Every region has a look of its own, with or without the dashes option
+        for dashes in &[false, true] {
+            let mut seen = HashSet::new();
+            for (region, style) in REGION_STYLES {
+                assert!(seen.insert(styleparts(*style, *dashes)), "{} looks like another region", region);
+            }
+        }
+        // And keeps it with all of them on one chart
+        let labels: Vec<(usize, &str)> = REGION_STYLES.iter().map(|(region, _)| *region).enumerate().collect();
+        let styles = assignstyles(&labels);
+        for (group, (_, style)) in REGION_STYLES.iter().enumerate() {
+            assert_eq!(styles[&group], *style);
+        }
+    }
+
+    #[test]
+    fn assignstyles_same_region_twice() {
+        // The first listed keeps the region's style; the other gets the first one free
+        let styles = assignstyles(&[(0, "Sedgwick County (NYT)"), (1, "Sedgwick County (JHU)")]);
+        assert_eq!(styles[&0], 1);
+        assert_eq!(styles[&1], 7);
+    }
+
+    #[test]
+    fn assignstyles_fills_unknown_in_order() {
+        // Styles kept for regions in REGION_STYLES are skipped, even if not on the chart
+        let styles = assignstyles(&[(0, "Butler County"), (1, "Sedgwick"), (2, "Reno County"), (3, "Ford County")]);
+        assert_eq!(styles[&1], 1);
+        assert_eq!(styles[&0], 7);
+        assert_eq!(styles[&2], 8);
+        assert_eq!(styles[&3], 15);
+    }
+
+    #[test]
+    fn describe_traces_by_day() {
+        // Day 104 is missing, so 7 days before day 110 is six points back
+        let x: Vec<i32> = (100..=110).filter(|day| *day != 104).collect();
+        let y: Vec<f64> = x.iter().map(|day| *day as f64).collect();
+        let traces = vec![Trace::new("A", 0, x, y), Trace::new("B", 1, vec![95, 102], vec![1.0, 2.0])];
+        let html = describe_traces("test", "Day", "Value", &traces);
+        assert!(html.contains("A: 110.00 at 110, +7.00 over 7 days;"), "{}", html);
+        assert!(!html.contains("over 14 days"), "{}", html);
+        // Rows are merged by x, in order, with blanks where a series has no value
+        let first = html.find("<th scope=\"row\">95</th><td></td><td>1.00</td>").unwrap();
+        let second = html.find("<th scope=\"row\">100</th><td>100.00</td><td></td>").unwrap();
+        let third = html.find("<th scope=\"row\">102</th><td>102.00</td><td>2.00</td>").unwrap();
+        assert!(first < second && second < third);
+        assert!(!html.contains("<th scope=\"row\">104</th>"));
+    }
+
+    #[test]
+    fn describe_traces_categories() {
+        let categories: Vec<String> = (0..10).map(|i| format!("c{}", i)).collect();
+        let traces = vec![Trace::new("A", 0, categories, vec![1.0; 10])];
+        let html = describe_traces("test", "Category", "Value", &traces);
+        assert!(html.contains("A: 1.00 at c9."), "{}", html);
+    }
+
+    /// Write contents to charts.toml under a name of its own in the temporary directory
+    fn tempconfig(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, contents).unwrap();
+        String::from(path.to_str().unwrap())
+    }
+
+    #[test]
+    fn default_smoothing_only_for_smoothed_charts() {
+        let path = tempconfig(
+            "covid19ks-charts-default.toml",
+            "[default]\nsmoothing = { method = \"centered\", window = 7, partial = \"drop\" }\n",
+        );
+        let config = ChartConfig::load(&path, &["smoothed"]);
+        let centered = Smoothing::Centered { window: 7, partial: Partial::Drop };
+        assert_eq!(config.apply("smoothed", &ChartOptions::new()).smoothing, centered);
+        assert_eq!(config.apply("unsmoothed", &ChartOptions::new()).smoothing, Smoothing::None);
+    }
+
+    #[test]
+    #[should_panic(expected = "[unsmoothed]: this chart doesn't take a smoothing option")]
+    fn smoothing_rejected_for_unsmoothed_chart() {
+        let path = tempconfig(
+            "covid19ks-charts-unsmoothed.toml",
+            "[unsmoothed]\nsmoothing = { method = \"trailing\", window = 7, partial = \"shrink\" }\n",
+        );
+        ChartConfig::load(&path, &["smoothed"]);
+    }
+
+    #[test]
+    fn note_survives_configured_subtitle() {
+        let path = tempconfig("covid19ks-charts-subtitle.toml", "[fitted]\nsubtitle = \"Configured\"\n");
+        let config = ChartConfig::load(&path, &[]);
+        // As a chart writer would, apply the configuration again to what the caller applied
+        let opts = config.apply("fitted", &ChartOptions::new().subtitle(Some(String::from("Code")))).note(Some(String::from("Note")));
+        let opts = config.apply("fitted", &opts);
+        assert_eq!(fulltitle("Title", &opts), "Title<br><sub>Configured; Note</sub>");
+        assert_eq!(fulltitle("Title", &ChartOptions::new()), "Title");
+    }
+
+    #[test]
+    #[should_panic(expected = "no-such-chart has no place in page::SECTIONS")]
+    fn unregistered_chart_refused() {
+        let mut out = Output {
+            bightml: File::create(std::env::temp_dir().join("covid19ks-all.html")).unwrap(),
+            config: Default::default(),
+            written: Vec::new(),
+        };
+        out.append("counties-100k-nyt", b"");
+        out.append("no-such-chart", b"");
+    }
 }
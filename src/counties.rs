@@ -17,6 +17,9 @@ Copyright (c) 2020 John Goerzen
 
  */
 
+use crate::db;
+use std::path::Path;
+
 #[derive(PartialEq, Debug)]
 pub struct Counties<'a> {
     pub clist: Vec<&'a str>,
@@ -33,9 +36,143 @@ impl<'a> Counties<'a> {
             "({})",
             self.clist
                 .iter()
-                .map(|x| format!("'{}'", x))
+                .map(|x| db::sqlquote(x))
                 .collect::<Vec<String>>()
                 .join(", ")
         )
     }
 }
+
+/// A named group of counties, such as a metro area or public health region.  Its incidence
+/// is computed from the total cases and total population of its members, so it can be
+/// charted anywhere a single county can.
+#[derive(PartialEq, Debug, Clone)]
+pub struct CountyGroup {
+    pub name: String,
+    pub clist: Vec<String>,
+}
+
+impl CountyGroup {
+    pub fn new(name: &str, list: Vec<&str>) -> CountyGroup {
+        CountyGroup {
+            name: String::from(name),
+            clist: list.into_iter().map(String::from).collect(),
+        }
+    }
+
+    /// Return the member counties of this group.
+    pub fn counties(&self) -> Counties<'_> {
+        Counties::new(self.clist.iter().map(|x| x.as_str()).collect())
+    }
+}
+
+/// Groups that are always available.
+pub fn builtin_groups() -> Vec<CountyGroup> {
+    vec![
+        CountyGroup::new("Wichita metro", vec!["Sedgwick", "Butler", "Harvey", "Sumner"]),
+        CountyGroup::new("Meatpacking counties", vec!["Ford", "Finney", "Seward"]),
+    ]
+}
+
+/// Load groups from a CSV file with a header row, where the keycol column names the group
+/// and the "county" column names one member county.  Groups are returned in the order they
+/// first appear.  If the file does not exist, there are no groups.
+fn load_keyed(path: &str, keycol: &str) -> Vec<CountyGroup> {
+    let mut groups: Vec<CountyGroup> = Vec::new();
+    if !Path::new(path).exists() {
+        return groups;
+    }
+    let mut rdr = csv::Reader::from_path(path).unwrap();
+    let headers = rdr.headers().unwrap().clone();
+    let keyidx = headers
+        .iter()
+        .position(|h| h.trim() == keycol)
+        .unwrap_or_else(|| panic!("{} has no {} column", path, keycol));
+    let countyidx = headers
+        .iter()
+        .position(|h| h.trim() == "county")
+        .unwrap_or_else(|| panic!("{} has no county column", path));
+    for result in rdr.records() {
+        let record = result.unwrap();
+        let (name, county) = (record[keyidx].trim(), record[countyidx].trim());
+        match groups.iter_mut().find(|g| g.name == name) {
+            Some(g) => g.clist.push(String::from(county)),
+            None => groups.push(CountyGroup::new(name, vec![county])),
+        }
+    }
+    groups
+}
+
+/// Load user-defined groups from a CSV file with "group" and "county" columns, one row
+/// per member county.  Groups are charted alongside counties and the builtin groups, so a
+/// group may not be named after either.  Every member must be one of the given counties;
+/// a misspelled one would otherwise be left out of the group's rate without notice.
+pub fn load_groups(path: &str, counties: &[&str]) -> Vec<CountyGroup> {
+    let groups = load_keyed(path, "group");
+    let builtin = builtin_groups();
+    for group in &groups {
+        if counties.contains(&group.name.as_str()) {
+            panic!("{}: group {} has the same name as a county", path, group.name);
+        }
+        if builtin.iter().any(|b| b.name == group.name) {
+            panic!("{}: group {} has the same name as a builtin group", path, group.name);
+        }
+        if let Some(county) = group.clist.iter().find(|c| !counties.contains(&c.as_str())) {
+            panic!("{}: group {} has unknown county {}", path, group.name, county);
+        }
+    }
+    groups
+}
+
+/// Load a county classification, such as urban/semi-urban/rural/frontier or RUCC codes,
+/// from a CSV file with "county" and "class" columns.  Each class is returned as a group
+/// of its counties.
+pub fn load_classes(path: &str) -> Vec<CountyGroup> {
+    load_keyed(path, "class")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    /// Write contents to a file of the given name in the temporary directory
+    fn tempcsv(name: &str, contents: &str) -> String {
+        let path = env::temp_dir().join(name);
+        fs::write(&path, contents).unwrap();
+        String::from(path.to_str().unwrap())
+    }
+
+    const COUNTIES: &[&str] = &["Sedgwick", "Butler", "Reno", "Harvey"];
+
+    #[test]
+    fn load_groups_in_order() {
+        let path = tempcsv("covid19ks-groups-ok.csv", "group,county\nSouth,Sedgwick\nSouth,Butler\nWest,Reno\n");
+        assert_eq!(
+            load_groups(&path, COUNTIES),
+            vec![CountyGroup::new("South", vec!["Sedgwick", "Butler"]), CountyGroup::new("West", vec!["Reno"])]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "group South has unknown county Sedgwik")]
+    fn load_groups_unknown_county() {
+        let path = tempcsv("covid19ks-groups-unknown.csv", "group,county\nSouth,Sedgwik\nSouth,Butler\n");
+        load_groups(&path, COUNTIES);
+    }
+
+    #[test]
+    #[should_panic(expected = "group Wichita metro has the same name as a builtin group")]
+    fn load_groups_builtin_name() {
+        let path = tempcsv("covid19ks-groups-builtin.csv", "group,county\nWichita metro,Sedgwick\n");
+        load_groups(&path, COUNTIES);
+    }
+
+    #[test]
+    #[should_panic(expected = "group Reno has the same name as a county")]
+    fn load_groups_county_name() {
+        let path = tempcsv("covid19ks-groups-county.csv", "group,county\nReno,Harvey\n");
+        load_groups(&path, COUNTIES);
+    }
+}
@@ -16,42 +16,217 @@ Copyright (c) 2020 John Goerzen
     along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
-use crate::counties::Counties;
+use crate::analysis::Positivity;
+use crate::counties::CountyGroup;
+use crate::localdata::PopulationOverrides;
+use futures::stream::{self, StreamExt};
 use sqlx::prelude::*;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
 
+/// If set, the full text of each query is printed along with its timing
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+/// Print the full text of each query from now on, not just what it was for
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+/// Print how long a query took, labeled with what it was for.  Since queries run
+/// concurrently, this is printed once the query completes, rather than before it starts.
+fn reporttiming(start: Instant, label: &str, query: &str) {
+    println!("[{:.3}s] {}", start.elapsed().as_secs_f64(), label);
+    if VERBOSE.load(Ordering::Relaxed) {
+        println!("{}", query);
+    }
+}
+
+/// Quote a string for SQL
+pub fn sqlquote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+/// SQL expression for the population of a row of nytcounties, with any overrides applied
+fn nytpopulation(pops: &PopulationOverrides) -> String {
+    let cases: Vec<String> = pops
+        .of_kind("county")
+        .map(|(county, pop)| format!("WHEN {} THEN {}", sqlquote(county), pop))
+        .collect();
+    match cases.is_empty() {
+        true => String::from("population"),
+        false => format!("(CASE county {} ELSE population END)", cases.join(" ")),
+    }
+}
+
+/// SQL expression for the population of a row of cdataset, with any overrides applied to
+/// the total-province rows of states and total-country rows of countries
+fn cdatasetpopulation(pops: &PopulationOverrides) -> String {
+    let mut cases: Vec<String> = pops
+        .of_kind("state")
+        .map(|(state, pop)| {
+            format!("WHEN location_type = 'total-province' AND province = {} THEN {}", sqlquote(state), pop)
+        })
+        .collect();
+    cases.extend(pops.of_kind("country").map(|(country, pop)| {
+        format!("WHEN location_type = 'total-country' AND country_code = {} THEN {}", sqlquote(country), pop)
+    }));
+    match cases.is_empty() {
+        true => String::from("factbook_population"),
+        false => format!("(CASE {} ELSE factbook_population END)", cases.join(" ")),
+    }
+}
 
 /// Read in the summarized data per-county, returning a HashMap of counties to a HashMap from date_julian to given field
 pub async fn getcountydata_100k_nytcounties(
+    pool: &sqlx::SqlitePool,
+    pops: &PopulationOverrides,
+    field: &str,
+    first_date: i32,
+    last_date: i32,
+) -> HashMap<String, HashMap<i32, f64>> {
+    let query = format!(
+        "SELECT county, date_julian, 100000.0 * CAST({} AS FLOAT) / CAST({} AS FLOAT) from nytcounties WHERE
+            state = 'Kansas'
+                  AND date_julian >= ? AND date_julian <= ?  AND county IS NOT NULL
+                ORDER BY county, date_julian",
+        field,
+        nytpopulation(pops)
+    );
+    let mut hm = HashMap::new();
+    let start = Instant::now();
+    let rows = sqlx::query_as::<_, (String, i32, f64)>(query.as_str())
+        .bind(first_date)
+        .bind(last_date)
+        .fetch_all(pool)
+        .await
+        .unwrap();
+    reporttiming(start, &format!("nytcounties {} per 100k", field), &query);
+    rows.into_iter().for_each(|(county, x, y)| {
+        hm.entry(county).or_insert_with(HashMap::new).insert(x, y);
+    });
+    hm
+}
+
+/// Like getcountydata_100k_nytcounties, but the counts themselves rather than rates
+pub async fn getcountydata_nytcounties(
     pool: &sqlx::SqlitePool,
     field: &str,
     first_date: i32,
     last_date: i32,
 ) -> HashMap<String, HashMap<i32, f64>> {
     let query = format!(
-        "SELECT county, date_julian, 100000.0 * CAST({} AS FLOAT) / CAST(population AS FLOAT) from nytcounties WHERE
+        "SELECT county, date_julian, CAST({} AS FLOAT) from nytcounties WHERE
             state = 'Kansas'
                   AND date_julian >= ? AND date_julian <= ?  AND county IS NOT NULL
                 ORDER BY county, date_julian",
         field
     );
     let mut hm = HashMap::new();
-    println!("{}", query);
-    sqlx::query_as::<_, (String, i32, f64)>(query.as_str())
+    let start = Instant::now();
+    let rows = sqlx::query_as::<_, (String, i32, f64)>(query.as_str())
         .bind(first_date)
         .bind(last_date)
         .fetch_all(pool)
         .await
-        .unwrap()
-        .into_iter()
-        .for_each(|(county, x, y)| {
-            hm.entry(county).or_insert(HashMap::new()).insert(x, y);
-        });
+        .unwrap();
+    reporttiming(start, &format!("nytcounties {}", field), &query);
+    rows.into_iter().for_each(|(county, x, y)| {
+        hm.entry(county).or_insert_with(HashMap::new).insert(x, y);
+    });
     hm
 }
 
+/// Like getcountydata_100k_nytcounties, but for groups of counties.  Each group's rate is
+/// the sum of the field over the total population of its members, not an average of the
+/// member counties' rates.  The population is that of all the members, even on days
+/// before some of them have data, so a group's early rate isn't inflated by dividing by
+/// only the counties reporting so far.  Returns a HashMap of group names to a HashMap from
+/// date_julian to the rate.
+pub async fn getgroupdata_100k_nytcounties(
+    pool: &sqlx::SqlitePool,
+    pops: &PopulationOverrides,
+    field: &str,
+    groups: &[CountyGroup],
+    first_date: i32,
+    last_date: i32,
+) -> HashMap<String, HashMap<i32, f64>> {
+    stream::iter(groups)
+        .map(|group| async move {
+            let members = group.counties().sqlclause();
+            let popquery = format!(
+                "SELECT CAST(SUM(pop) AS FLOAT) FROM
+                    (SELECT MAX({}) AS pop FROM nytcounties
+                        WHERE state = 'Kansas' AND county IN {} AND population IS NOT NULL
+                        GROUP BY county)",
+                nytpopulation(pops),
+                members
+            );
+            let query = format!(
+                "SELECT date_julian, CAST(SUM({}) AS FLOAT) from nytcounties WHERE
+                    state = 'Kansas' AND county IN {}
+                          AND date_julian >= ? AND date_julian <= ?
+                        GROUP BY date_julian ORDER BY date_julian",
+                field, members
+            );
+            let start = Instant::now();
+            let (population,) = sqlx::query_as::<_, (f64,)>(popquery.as_str())
+                .fetch_one(pool)
+                .await
+                .unwrap_or_else(|e| panic!("No population for group {}: {}", group.name, e));
+            reporttiming(start, &format!("nytcounties population of {}", group.name), &popquery);
+            let start = Instant::now();
+            let rows = sqlx::query_as::<_, (i32, f64)>(query.as_str())
+                .bind(first_date)
+                .bind(last_date)
+                .fetch_all(pool)
+                .await
+                .unwrap();
+            reporttiming(start, &format!("nytcounties {} for {}", field, group.name), &query);
+            let rates = rows
+                .into_iter()
+                .map(|(day, total)| (day, 100000.0 * total / population))
+                .collect();
+            (group.name.clone(), rates)
+        })
+        .buffer_unordered(pool.max_size() as usize)
+        .collect()
+        .await
+}
+
 /// Read in the summarized data per-county, returning a HashMap of counties to a HashMap from date_julian to given field
 pub async fn getgeneralmaskdata_100k(
+    pool: &sqlx::SqlitePool,
+    pops: &PopulationOverrides,
+    dataset: &str,
+    field: &str,
+    where_clause: &str,
+    first_date: i32,
+    last_date: i32,
+) -> HashMap<i32, f64> {
+    let query = format!(
+        "SELECT date_julian, 100000.0 * CAST(SUM({}) AS FLOAT) / CAST(SUM({}) AS FLOAT) FROM cdataset
+            WHERE dataset = ? AND {}
+                  AND date_julian >= ? AND date_julian <= ?  AND administrative IS NOT NULL
+            GROUP BY date_julian ORDER BY date_julian",
+        field,
+        cdatasetpopulation(pops),
+        where_clause
+    );
+    let start = Instant::now();
+    let rows = sqlx::query_as::<_, (i32, f64)>(query.as_str())
+        .bind(dataset)
+        .bind(first_date)
+        .bind(last_date)
+        .fetch_all(pool)
+        .await
+        .unwrap();
+    reporttiming(start, &format!("{} {} per 100k", dataset, field), &query);
+    rows.into_iter().collect()
+}
+
+/// Like getgeneralmaskdata_100k, but returns the sum of the field itself rather than a rate
+pub async fn getgeneraldata(
     pool: &sqlx::SqlitePool,
     dataset: &str,
     field: &str,
@@ -60,22 +235,74 @@ pub async fn getgeneralmaskdata_100k(
     last_date: i32,
 ) -> HashMap<i32, f64> {
     let query = format!(
-        "SELECT date_julian, 100000.0 * CAST(SUM({}) AS FLOAT) / CAST(SUM(factbook_population) AS FLOAT) FROM cdataset
+        "SELECT date_julian, CAST(SUM({}) AS FLOAT) FROM cdataset
             WHERE dataset = ? AND {}
                   AND date_julian >= ? AND date_julian <= ?  AND administrative IS NOT NULL
             GROUP BY date_julian ORDER BY date_julian",
         field, where_clause
     );
-    println!("{}", query);
-    sqlx::query_as::<_, (i32, f64)>(query.as_str())
+    let start = Instant::now();
+    let rows = sqlx::query_as::<_, (i32, f64)>(query.as_str())
         .bind(dataset)
         .bind(first_date)
         .bind(last_date)
         .fetch_all(pool)
         .await
-        .unwrap()
-        .into_iter()
-        .collect()
+        .unwrap();
+    reporttiming(start, &format!("{} {}", dataset, field), &query);
+    rows.into_iter().collect()
+}
+
+/// Sum a field over all the counties of a state in nytcounties, including cases NYT could
+/// not assign to a county
+pub async fn getstatedata_nytcounties(
+    pool: &sqlx::SqlitePool,
+    state: &str,
+    field: &str,
+    first_date: i32,
+    last_date: i32,
+) -> HashMap<i32, f64> {
+    let query = format!(
+        "SELECT date_julian, CAST(SUM({}) AS FLOAT) from nytcounties WHERE
+            state = ? AND date_julian >= ? AND date_julian <= ?
+                GROUP BY date_julian ORDER BY date_julian",
+        field
+    );
+    let start = Instant::now();
+    let rows = sqlx::query_as::<_, (i32, f64)>(query.as_str())
+        .bind(state)
+        .bind(first_date)
+        .bind(last_date)
+        .fetch_all(pool)
+        .await
+        .unwrap();
+    reporttiming(start, &format!("nytcounties {} for {}", field, state), &query);
+    rows.into_iter().collect()
+}
+
+/// Read a field from covidtracking for a state.  Days on which it was not reported are left out.
+pub async fn getfield_covidtracking(
+    pool: &sqlx::SqlitePool,
+    state: &str,
+    field: &str,
+    first_date: i32,
+    last_date: i32,
+) -> HashMap<i32, f64> {
+    let query = format!(
+        "SELECT date_julian, CAST({} AS FLOAT) from covidtracking
+            where state = ? AND date_julian >= ? AND date_julian <= ? AND {} IS NOT NULL order by date_julian",
+        field, field
+    );
+    let start = Instant::now();
+    let rows = sqlx::query_as::<_, (i32, f64)>(query.as_str())
+        .bind(state)
+        .bind(first_date)
+        .bind(last_date)
+        .fetch_all(pool)
+        .await
+        .unwrap();
+    reporttiming(start, &format!("covidtracking {} for {}", field, state), &query);
+    rows.into_iter().collect()
 }
 
 pub async fn gettestdata(
@@ -88,17 +315,92 @@ pub async fn gettestdata(
         "SELECT date_julian, positiveIncrease, totalTestResultsIncrease from covidtracking
             where state = ? AND date_julian >= ? AND date_julian <= ? order by date_julian"
     ;
-    println!("{}", querystr);
-
+    let start = Instant::now();
     let query = sqlx::query_as::<_, (i32, i64, i64)>(querystr);
-    query
+    let rows = query
+        .bind(state)
+        .bind(first_date)
+        .bind(last_date)
+        .fetch_all(pool)
+        .await
+        .unwrap();
+    reporttiming(start, &format!("covidtracking tests for {}", state), querystr);
+    rows.into_iter()
+        .map(|(date, pos, tot)| (date, (pos, tot)))
+        .collect()
+}
+
+/// Read daily (positive, total) test counts from covidtracking for positivity by the given
+/// method.  Cumulative columns are differenced; days where either column is missing, on
+/// that day or the day before, are left out.
+pub async fn getpositivitydata_covidtracking(
+    pool: &sqlx::SqlitePool,
+    state: &str,
+    method: Positivity,
+    first_date: i32,
+    last_date: i32,
+) -> HashMap<i32, (i64, i64)> {
+    let (poscol, totcol, cumulative) = method.covidtracking_columns();
+    let query = format!(
+        "SELECT date_julian, {}, {} from covidtracking
+            where state = ? AND date_julian >= ? AND date_julian <= ? order by date_julian",
+        poscol, totcol
+    );
+    let start = Instant::now();
+    let rows = sqlx::query_as::<_, (i32, Option<i64>, Option<i64>)>(query.as_str())
         .bind(state)
+        .bind(if cumulative { first_date - 1 } else { first_date })
+        .bind(last_date)
+        .fetch_all(pool)
+        .await
+        .unwrap();
+    reporttiming(start, &format!("covidtracking {:?} positivity for {}", method, state), &query);
+    let mut hm = HashMap::new();
+    let mut previous: Option<(i32, i64, i64)> = None;
+    for (date, pos, tot) in rows {
+        if let (Some(pos), Some(tot)) = (pos, tot) {
+            if !cumulative {
+                hm.insert(date, (pos, tot));
+            } else if let Some((pdate, ppos, ptot)) = previous {
+                if pdate + 1 == date {
+                    hm.insert(date, (pos - ppos, tot - ptot));
+                }
+            }
+            previous = Some((date, pos, tot));
+        }
+    }
+    hm
+}
+
+/// Read daily (positive, total) test counts from owid for positivity by the given method.
+/// Returns nothing if owid doesn't have what the method needs.
+pub async fn getpositivitydata_owid(
+    pool: &sqlx::SqlitePool,
+    country: &str,
+    method: Positivity,
+    first_date: i32,
+    last_date: i32,
+) -> HashMap<i32, (i64, i64)> {
+    let (poscol, totcol) = match method.owid_columns() {
+        Some(cols) => cols,
+        None => return HashMap::new(),
+    };
+    let query = format!(
+        "SELECT date_julian, CAST({} AS INTEGER), CAST({} AS INTEGER) from owid
+            where iso_code = ? AND date_julian >= ? AND date_julian <= ?
+                  AND {} IS NOT NULL AND {} IS NOT NULL order by date_julian",
+        poscol, totcol, poscol, totcol
+    );
+    let start = Instant::now();
+    let rows = sqlx::query_as::<_, (i32, i64, i64)>(query.as_str())
+        .bind(country)
         .bind(first_date)
         .bind(last_date)
         .fetch_all(pool)
         .await
-        .unwrap()
-        .into_iter()
+        .unwrap();
+    reporttiming(start, &format!("owid {:?} positivity for {}", method, country), &query);
+    rows.into_iter()
         .map(|(date, pos, tot)| (date, (pos, tot)))
         .collect()
 }
@@ -111,17 +413,133 @@ pub async fn gettestdata_owid(
 ) -> HashMap<i32, (i64, i64)> {
     let querystr = "SELECT date_julian, new_cases, new_tests from owid
             where iso_code = ? AND date_julian >= ? AND date_julian <= ? order by date_julian";
-    println!("{}", querystr);
-
+    let start = Instant::now();
     let query = sqlx::query_as::<_, (i32, i64, i64)>(querystr);
-    query
+    let rows = query
         .bind(country)
         .bind(first_date)
         .bind(last_date)
         .fetch_all(pool)
         .await
-        .unwrap()
-        .into_iter()
+        .unwrap();
+    reporttiming(start, &format!("owid tests for {}", country), querystr);
+    rows.into_iter()
         .map(|(date, pos, tot)| (date, (pos, tot)))
         .collect()
 }
+
+/// The latest day with data in any of the tables we chart from, if there is any
+pub async fn getlastdate(pool: &sqlx::SqlitePool) -> Option<i32> {
+    let query = "SELECT MAX(last) FROM
+        (SELECT MAX(date_julian) AS last FROM nytcounties
+            UNION ALL SELECT MAX(date_julian) FROM cdataset
+            UNION ALL SELECT MAX(date_julian) FROM covidtracking
+            UNION ALL SELECT MAX(date_julian) FROM owid)";
+    let start = Instant::now();
+    let (last,) = sqlx::query_as::<_, (Option<i32>,)>(query)
+        .fetch_one(pool)
+        .await
+        .unwrap();
+    reporttiming(start, "last date", query);
+    last
+}
+
+/// owid's three-letter ISO codes for the countries we chart, and the two-letter codes that
+/// cdataset and population overrides ("country:US") use for them
+const OWID_COUNTRY_CODES: &[(&str, &str)] = &[("USA", "US"), ("CAN", "CA"), ("GBR", "GB"), ("FRA", "FR"), ("TWN", "TW")];
+
+/// The population override, if any, for an owid country given by its ISO code
+fn owidpopulation_override(pops: &PopulationOverrides, country: &str) -> Option<f64> {
+    let (_, code) = OWID_COUNTRY_CODES.iter().find(|(iso, _)| *iso == country)?;
+    pops.get(&format!("country:{}", code))
+}
+
+/// Read the population of a country from owid, if it has one, with any override applied
+pub async fn getpopulation_owid(pool: &sqlx::SqlitePool, pops: &PopulationOverrides, country: &str) -> Option<f64> {
+    if let Some(population) = owidpopulation_override(pops, country) {
+        return Some(population);
+    }
+    let query = "SELECT CAST(MAX(population) AS FLOAT) from owid where iso_code = ?";
+    let start = Instant::now();
+    let (population,) = sqlx::query_as::<_, (Option<f64>,)>(query)
+        .bind(country)
+        .fetch_one(pool)
+        .await
+        .unwrap();
+    reporttiming(start, &format!("owid population of {}", country), query);
+    population
+}
+
+/// True if the table has all of the given columns.  Older releases of the database lack
+/// some of the newer columns, such as owid's vaccination figures.
+pub async fn hascolumns(pool: &sqlx::SqlitePool, table: &str, columns: &[&str]) -> bool {
+    let query = format!("SELECT name FROM pragma_table_info({})", sqlquote(table));
+    let start = Instant::now();
+    let rows = sqlx::query_as::<_, (String,)>(query.as_str())
+        .fetch_all(pool)
+        .await
+        .unwrap();
+    reporttiming(start, &format!("columns of {}", table), &query);
+    columns.iter().all(|column| rows.iter().any(|(name,)| name == column))
+}
+
+/// Read a vaccination field, such as people_vaccinated_per_hundred, for a country from owid.
+/// Days on which the field was not reported are left out.
+pub async fn getvaccdata_owid(
+    pool: &sqlx::SqlitePool,
+    country: &str,
+    field: &str,
+    first_date: i32,
+    last_date: i32,
+) -> HashMap<i32, f64> {
+    let query = format!(
+        "SELECT date_julian, CAST({} AS FLOAT) from owid
+            where iso_code = ? AND date_julian >= ? AND date_julian <= ? AND {} IS NOT NULL order by date_julian",
+        field, field
+    );
+    let start = Instant::now();
+    let rows = sqlx::query_as::<_, (i32, f64)>(query.as_str())
+        .bind(country)
+        .bind(first_date)
+        .bind(last_date)
+        .fetch_all(pool)
+        .await
+        .unwrap();
+    reporttiming(start, &format!("owid {} for {}", field, country), &query);
+    rows.into_iter().collect()
+}
+
+/// Read the population of each Kansas county from nytcounties, with any overrides applied
+pub async fn getcountypopulation_nytcounties(pool: &sqlx::SqlitePool, pops: &PopulationOverrides) -> HashMap<String, f64> {
+    let query = format!(
+        "SELECT county, CAST(MAX({}) AS FLOAT) from nytcounties
+            WHERE state = 'Kansas' AND county IS NOT NULL AND population IS NOT NULL
+            GROUP BY county",
+        nytpopulation(pops)
+    );
+    let start = Instant::now();
+    let rows = sqlx::query_as::<_, (String, f64)>(query.as_str())
+        .fetch_all(pool)
+        .await
+        .unwrap();
+    reporttiming(start, "nytcounties county populations", &query);
+    rows.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn owid_overrides_by_country_code() {
+        let pops = PopulationOverrides {
+            entries: vec![
+                (String::from("country:US"), 328239523.0, String::from("Census 2019")),
+                (String::from("state:Kansas"), 2913314.0, String::from("Census 2019")),
+            ],
+        };
+        assert_eq!(owidpopulation_override(&pops, "USA"), Some(328239523.0));
+        assert_eq!(owidpopulation_override(&pops, "CAN"), None);
+        assert_eq!(owidpopulation_override(&pops, "US"), None);
+    }
+}
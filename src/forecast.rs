@@ -0,0 +1,183 @@
+/* Forecasting
+
+Copyright (c) 2020 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+ */
+
+use crate::analysis::{largestkey, Interval, Z_95};
+use std::collections::HashMap;
+
+/// Number of days of recent data the forecasts on our charts are fit to
+pub const FITDAYS: i32 = 21;
+
+/// Number of days the forecasts on our charts project forward
+pub const HORIZON: i32 = 14;
+
+/// Forecast HORIZON days past the end of hm, fit to its last FITDAYS days.
+pub fn latest(hm: &HashMap<i32, f64>) -> Option<Interval> {
+    loglinear(hm, *largestkey(hm)?, FITDAYS, HORIZON)
+}
+
+/// Project hm forward horizon days past asof, by fitting a line to the logarithm of the
+/// fitdays days ending on asof -- that is, assuming recent exponential growth or decline
+/// continues.  This is meant for already-smoothed data such as a 7-day moving average.
+///
+/// The result includes asof itself, at its actual value, so that it joins up with the
+/// data when charted.  Days that are zero or missing are left out of the fit; if fewer
+/// than half of the days remain, there is no forecast.
+///
+/// Consecutive days of a moving average are not independent, so the intervals here are
+/// narrower than they should be.  Use backtest to see how often they actually hold.
+pub fn loglinear(hm: &HashMap<i32, f64>, asof: i32, fitdays: i32, horizon: i32) -> Option<Interval> {
+    let points: Vec<(f64, f64)> = ((asof - fitdays + 1)..=asof)
+        .filter_map(|day| hm.get(&day).filter(|y| **y > 0.0).map(|y| ((day - asof) as f64, y.ln())))
+        .collect();
+    let n = points.len() as f64;
+    if points.len() < 3 || points.len() * 2 < fitdays as usize {
+        return None;
+    }
+    let tbar = points.iter().map(|(t, _)| t).sum::<f64>() / n;
+    let ybar = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let sxx = points.iter().map(|(t, _)| (t - tbar).powi(2)).sum::<f64>();
+    let sxy = points.iter().map(|(t, y)| (t - tbar) * (y - ybar)).sum::<f64>();
+    let slope = sxy / sxx;
+    let intercept = ybar - slope * tbar;
+    let sse = points
+        .iter()
+        .map(|(t, y)| (y - intercept - slope * t).powi(2))
+        .sum::<f64>();
+    let s2 = sse / (n - 2.0);
+
+    let mut ret = Interval::default();
+    let last = *hm.get(&asof)?;
+    ret.center.insert(asof, last);
+    ret.lower.insert(asof, last);
+    ret.upper.insert(asof, last);
+    for h in 1..=horizon {
+        let t = h as f64;
+        let yhat = intercept + slope * t;
+        let se = (s2 * (1.0 + 1.0 / n + (t - tbar).powi(2) / sxx)).sqrt();
+        ret.center.insert(asof + h, yhat.exp());
+        ret.lower.insert(asof + h, (yhat - Z_95 * se).exp());
+        ret.upper.insert(asof + h, (yhat + Z_95 * se).exp());
+    }
+    Some(ret)
+}
+
+/// How forecasts made on past days compared with what actually happened
+#[derive(Debug)]
+pub struct BacktestScore {
+    /// Number of forecasts scored
+    pub forecasts: usize,
+    /// Mean absolute percentage error
+    pub mape: f64,
+    /// Fraction of actual values that fell within the prediction interval
+    pub coverage: f64,
+}
+
+/// Make a forecast as of each day from first_asof through last_asof, and score the
+/// projection for horizon days later against the actual value on that day.  Returns None
+/// if no forecasts could be scored.
+pub fn backtest(
+    hm: &HashMap<i32, f64>,
+    first_asof: i32,
+    last_asof: i32,
+    fitdays: i32,
+    horizon: i32,
+) -> Option<BacktestScore> {
+    let mut forecasts = 0;
+    let mut errorsum = 0.0;
+    let mut covered = 0;
+    for asof in first_asof..=last_asof {
+        let actual = match hm.get(&(asof + horizon)) {
+            Some(actual) if *actual > 0.0 => *actual,
+            _ => continue,
+        };
+        if let Some(forecast) = loglinear(hm, asof, fitdays, horizon) {
+            let day = asof + horizon;
+            forecasts += 1;
+            errorsum += (forecast.center[&day] - actual).abs() / actual;
+            if forecast.lower[&day] <= actual && actual <= forecast.upper[&day] {
+                covered += 1;
+            }
+        }
+    }
+    if forecasts == 0 {
+        return None;
+    }
+    Some(BacktestScore {
+        forecasts,
+        mape: 100.0 * errorsum / forecasts as f64,
+        coverage: covered as f64 / forecasts as f64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{assert_close, series};
+
+    /// 10 * 2^(day / 7) -- doubling weekly
+    fn doubling(days: usize) -> HashMap<i32, f64> {
+        series(&(0..days).map(|i| 10.0 * 2f64.powf(i as f64 / 7.0)).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn loglinear_exact_growth() {
+        // An exact exponential is projected exactly, with no uncertainty
+        let hm = doubling(21);
+        let forecast = loglinear(&hm, 120, 21, 7).unwrap();
+        let expected: Vec<(i32, f64)> = (120..=127).map(|day| (day, 10.0 * 2f64.powf((day - 100) as f64 / 7.0))).collect();
+        assert_close(&forecast.center, &expected);
+        assert_close(&forecast.lower, &expected);
+        assert_close(&forecast.upper, &expected);
+    }
+
+    #[test]
+    fn loglinear_interval_widens() {
+        let hm = series(&(0..21).map(|i| [9.0, 11.0, 10.0][i % 3] * (1.05f64).powi(i as i32)).collect::<Vec<_>>());
+        let forecast = loglinear(&hm, 120, 21, 14).unwrap();
+        // The forecast starts from the last value, and its interval grows with the horizon
+        assert_eq!(forecast.center[&120], hm[&120]);
+        let width = |day: i32| forecast.upper[&day] / forecast.lower[&day];
+        assert!(forecast.lower[&121] < forecast.center[&121] && forecast.center[&121] < forecast.upper[&121]);
+        assert!(width(121) < width(127) && width(127) < width(134));
+        // and the growth rate is recovered
+        assert!((forecast.center[&134] / forecast.center[&133] - 1.05).abs() < 0.01);
+    }
+
+    #[test]
+    fn loglinear_too_few_days() {
+        // Zeros are left out of the fit, leaving fewer than half the days
+        let mut hm = doubling(21);
+        for day in 100..111 {
+            hm.insert(day, 0.0);
+        }
+        assert!(loglinear(&hm, 120, 21, 7).is_none());
+        assert!(loglinear(&hm, 130, 21, 7).is_none());
+    }
+
+    #[test]
+    fn backtest_exact_growth() {
+        // Forecasts as of days 120..=130 are scored against days 127..=137
+        let hm = doubling(38);
+        let score = backtest(&hm, 120, 130, 21, 7).unwrap();
+        assert_eq!(score.forecasts, 11);
+        assert!(score.mape < 1e-9);
+        // With no actual value to score against, there are no forecasts
+        assert!(backtest(&hm, 131, 140, 21, 7).is_none());
+    }
+}
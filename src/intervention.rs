@@ -0,0 +1,494 @@
+/* Interrupted time series
+
+Copyright (c) 2020 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+ */
+
+use crate::analysis::{calcdifference, Z_95};
+use std::collections::HashMap;
+
+/// Days of autocorrelation allowed for in the standard errors.  Daily case counts are
+/// correlated across at least a week of reporting.
+const HAC_LAG: usize = 7;
+
+/// An estimate with its approximately 95% confidence interval
+#[derive(Debug, Clone, Copy)]
+pub struct Estimate {
+    pub value: f64,
+    pub lower: f64,
+    pub upper: f64,
+}
+
+/// A segmented regression fit around a policy date
+#[derive(Debug)]
+pub struct SegmentedFit {
+    /// Immediate change in level when the policy took effect
+    pub level: Estimate,
+    /// Change in the daily trend after the policy took effect
+    pub slope: Estimate,
+    /// The fitted values, before and after
+    pub fitted: HashMap<i32, f64>,
+    /// The trend before the policy, continued past it as if there had been no policy
+    pub counterfactual: HashMap<i32, f64>,
+}
+
+/// Invert a small square matrix by Gauss-Jordan elimination.  None if it is singular.
+fn invert(m: &[Vec<f64>]) -> Option<Vec<Vec<f64>>> {
+    let n = m.len();
+    let mut a: Vec<Vec<f64>> = m
+        .iter()
+        .enumerate()
+        .map(|(i, row)| {
+            let mut row = row.clone();
+            row.extend((0..n).map(|j| if i == j { 1.0 } else { 0.0 }));
+            row
+        })
+        .collect();
+    for col in 0..n {
+        let pivot = (col..n).max_by(|x, y| a[*x][col].abs().partial_cmp(&a[*y][col].abs()).unwrap())?;
+        if a[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        let p = a[col][col];
+        a[col].iter_mut().for_each(|x| *x /= p);
+        for row in 0..n {
+            if row != col {
+                let factor = a[row][col];
+                let pivotrow = a[col].clone();
+                a[row].iter_mut().zip(pivotrow).for_each(|(x, y)| *x -= factor * y);
+            }
+        }
+    }
+    Some(a.into_iter().map(|row| row[n..].to_vec()).collect())
+}
+
+/// Least squares fit of y on the columns of x.  Returns the coefficients and their
+/// covariance, by the Newey-West estimator so that autocorrelated errors don't make the
+/// estimates look more certain than they are.  The rows must be in time order.
+fn ols(x: &[Vec<f64>], y: &[f64]) -> Option<(Vec<f64>, Vec<Vec<f64>>)> {
+    let k = x.first()?.len();
+    if x.len() <= k {
+        return None;
+    }
+    let mut xtx = vec![vec![0.0; k]; k];
+    let mut xty = vec![0.0; k];
+    for (row, yval) in x.iter().zip(y) {
+        for i in 0..k {
+            xty[i] += row[i] * yval;
+            for j in 0..k {
+                xtx[i][j] += row[i] * row[j];
+            }
+        }
+    }
+    let xtxinv = invert(&xtx)?;
+    let beta: Vec<f64> = (0..k).map(|i| (0..k).map(|j| xtxinv[i][j] * xty[j]).sum()).collect();
+    let resid: Vec<f64> = x
+        .iter()
+        .zip(y)
+        .map(|(row, yval)| yval - row.iter().zip(&beta).map(|(a, b)| a * b).sum::<f64>())
+        .collect();
+
+    // S = sum over lags l of w_l * sum_t e_t e_{t-l} (x_t x_{t-l}' + x_{t-l} x_t'), with
+    // Bartlett weights, counting lag 0 once
+    let mut s = vec![vec![0.0; k]; k];
+    for lag in 0..=HAC_LAG.min(x.len() - 1) {
+        let weight = 1.0 - (lag as f64) / (HAC_LAG as f64 + 1.0);
+        for t in lag..x.len() {
+            let e = resid[t] * resid[t - lag];
+            for i in 0..k {
+                for j in 0..k {
+                    let term = x[t][i] * x[t - lag][j] + if lag > 0 { x[t - lag][i] * x[t][j] } else { 0.0 };
+                    s[i][j] += weight * e * term;
+                }
+            }
+        }
+    }
+    let mul = |a: &Vec<Vec<f64>>, b: &Vec<Vec<f64>>| -> Vec<Vec<f64>> {
+        (0..k)
+            .map(|i| (0..k).map(|j| (0..k).map(|m| a[i][m] * b[m][j]).sum()).collect())
+            .collect()
+    };
+    let cov = mul(&mul(&xtxinv, &s), &xtxinv);
+    Some((beta, cov))
+}
+
+/// Fit a segmented regression to hm over the before days before policyday and the after
+/// days from it on: a line before the policy, with a change in level and in slope when
+/// it takes effect.  Pass daily data that hasn't been smoothed; smoothing would blur the
+/// change in level across the window.  Days missing from hm are left out.  Returns None
+/// if there isn't enough data on both sides.
+pub fn segmented(hm: &HashMap<i32, f64>, policyday: i32, before: i32, after: i32) -> Option<SegmentedFit> {
+    let days: Vec<i32> = (policyday - before..policyday + after).filter(|day| hm.contains_key(day)).collect();
+    let beforecount = days.iter().filter(|day| **day < policyday).count();
+    if beforecount < 3 || days.len() - beforecount < 3 {
+        return None;
+    }
+    // Intercept, time since the policy, after the policy, and time since it when after
+    let row = |day: i32| -> Vec<f64> {
+        let t = (day - policyday) as f64;
+        let post = if day >= policyday { 1.0 } else { 0.0 };
+        vec![1.0, t, post, t * post]
+    };
+    let x: Vec<Vec<f64>> = days.iter().map(|day| row(*day)).collect();
+    let y: Vec<f64> = days.iter().map(|day| hm[day]).collect();
+    let (beta, cov) = ols(&x, &y)?;
+    let estimate = |i: usize| {
+        let se = cov[i][i].max(0.0).sqrt();
+        Estimate { value: beta[i], lower: beta[i] - Z_95 * se, upper: beta[i] + Z_95 * se }
+    };
+    let fit = |day: i32, coefs: usize| row(day).iter().zip(&beta).take(coefs).map(|(a, b)| a * b).sum::<f64>();
+    Some(SegmentedFit {
+        level: estimate(2),
+        slope: estimate(3),
+        fitted: (policyday - before..policyday + after).map(|day| (day, fit(day, 4))).collect(),
+        counterfactual: (policyday..policyday + after).map(|day| (day, fit(day, 2))).collect(),
+    })
+}
+
+/// Difference-in-differences: the segmented regression of the difference between the
+/// treated and comparison series, so that what the two had in common, such as a statewide
+/// surge, doesn't count as an effect of the policy.
+pub fn diffindiff(
+    treated: &HashMap<i32, f64>,
+    comparison: &HashMap<i32, f64>,
+    policyday: i32,
+    before: i32,
+    after: i32,
+) -> Option<SegmentedFit> {
+    segmented(&calcdifference(treated, comparison), policyday, before, after)
+}
+
+/// Most iterations of the weight search for a synthetic control.  The weights settle well
+/// before this for the few dozen donors in a state.
+const SYNTH_ITERATIONS: usize = 2000;
+
+/// The weight search stops once no weight changes by more than this in an iteration
+const SYNTH_TOLERANCE: f64 = 1e-9;
+
+/// A synthetic control: the weighted average of donor regions that best matches the
+/// treated region before a policy, standing in for it afterward
+#[derive(Debug)]
+pub struct SyntheticControl {
+    /// Each donor's weight, largest first, leaving out those with none
+    pub weights: Vec<(String, f64)>,
+    /// The weighted average of the donors, before and after
+    pub synthetic: HashMap<i32, f64>,
+    /// Root mean squared difference between the treated region and the synthetic
+    /// control before the policy
+    pub rmspe_before: f64,
+    /// Root mean squared difference after it
+    pub rmspe_after: f64,
+    /// Mean difference between the treated region and the synthetic control after the
+    /// policy; negative if the treated region did better
+    pub gap: f64,
+    /// Share of regions, the treated one among them, whose ratio of after to before
+    /// RMSPE is as large as the treated region's when each donor in turn is treated as if
+    /// it had had the policy.  Small values mean the gap would be unusual by chance.
+    pub placebo_p: f64,
+}
+
+/// Project v onto the set of non-negative weights summing to 1
+fn projectsimplex(v: &[f64]) -> Vec<f64> {
+    let mut sorted = v.to_vec();
+    sorted.sort_by(|a, b| b.partial_cmp(a).unwrap());
+    let mut cumsum = 0.0;
+    let mut theta = 0.0;
+    for (i, val) in sorted.iter().enumerate() {
+        cumsum += val;
+        let t = (cumsum - 1.0) / (i as f64 + 1.0);
+        if val - t > 0.0 {
+            theta = t;
+        }
+    }
+    v.iter().map(|x| (x - theta).max(0.0)).collect()
+}
+
+/// Non-negative weights summing to 1 for the columns of donors (one row per day) that
+/// minimize the squared difference from target, by accelerated projected gradient descent.
+/// Stops once the weights converge.
+fn simplexweights(donors: &[Vec<f64>], target: &[f64]) -> Vec<f64> {
+    let n = donors.first().map_or(0, |row| row.len());
+    // Gradient of the squared error is 2 (G w - b)
+    let mut g = vec![vec![0.0; n]; n];
+    let mut b = vec![0.0; n];
+    for (row, y) in donors.iter().zip(target) {
+        for i in 0..n {
+            b[i] += row[i] * y;
+            for j in 0..n {
+                g[i][j] += row[i] * row[j];
+            }
+        }
+    }
+    // The largest row sum bounds the largest eigenvalue of G, giving a safe step
+    let bound = g.iter().map(|row| row.iter().map(|x| x.abs()).sum::<f64>()).fold(0.0, f64::max);
+    if bound == 0.0 {
+        return vec![1.0 / n as f64; n];
+    }
+    let step = 1.0 / bound;
+    let mut w = vec![1.0 / n as f64; n];
+    let mut momentum = w.clone();
+    let mut t: f64 = 1.0;
+    for _ in 0..SYNTH_ITERATIONS {
+        let grad: Vec<f64> = (0..n)
+            .map(|i| g[i].iter().zip(&momentum).map(|(a, b)| a * b).sum::<f64>() - b[i])
+            .collect();
+        let next = projectsimplex(&momentum.iter().zip(&grad).map(|(m, d)| m - step * d).collect::<Vec<f64>>());
+        let tnext = (1.0 + (1.0 + 4.0 * t * t).sqrt()) / 2.0;
+        momentum = next.iter().zip(&w).map(|(x, prev)| x + (t - 1.0) / tnext * (x - prev)).collect();
+        let change = next.iter().zip(&w).map(|(x, prev)| (x - prev).abs()).fold(0.0, f64::max);
+        w = next;
+        t = tnext;
+        if change < SYNTH_TOLERANCE {
+            break;
+        }
+    }
+    w
+}
+
+/// The synthetic control for treated from donors, fit over the before days before
+/// policyday.  Returns the weighted average of the donors and the RMSPE before and from
+/// policyday on; only days that treated and every donor have are used.
+#[allow(clippy::type_complexity)]
+fn fitsynthetic(
+    treated: &HashMap<i32, f64>,
+    donors: &[(&str, &HashMap<i32, f64>)],
+    policyday: i32,
+    before: i32,
+) -> Option<(Vec<f64>, HashMap<i32, f64>, f64, f64)> {
+    let common = |day: &i32| treated.contains_key(day) && donors.iter().all(|(_, hm)| hm.contains_key(day));
+    let beforedays: Vec<i32> = (policyday - before..policyday).filter(common).collect();
+    if beforedays.len() < 7 || donors.is_empty() {
+        return None;
+    }
+    let matrix: Vec<Vec<f64>> = beforedays.iter().map(|day| donors.iter().map(|(_, hm)| hm[day]).collect()).collect();
+    let target: Vec<f64> = beforedays.iter().map(|day| treated[day]).collect();
+    let weights = simplexweights(&matrix, &target);
+
+    let mut days: Vec<i32> = treated.keys().cloned().filter(|day| *day >= policyday - before && common(day)).collect();
+    days.sort_unstable();
+    let synthetic: HashMap<i32, f64> = days
+        .iter()
+        .map(|day| (*day, donors.iter().zip(&weights).map(|((_, hm), w)| hm[day] * w).sum()))
+        .collect();
+    let rmspe = |days: &mut dyn Iterator<Item = &i32>| {
+        let sq: Vec<f64> = days.map(|day| (treated[day] - synthetic[day]).powi(2)).collect();
+        (sq.iter().sum::<f64>() / sq.len().max(1) as f64).sqrt()
+    };
+    let rmspe_before = rmspe(&mut days.iter().filter(|day| **day < policyday));
+    let rmspe_after = rmspe(&mut days.iter().filter(|day| **day >= policyday));
+    Some((weights, synthetic, rmspe_before, rmspe_after))
+}
+
+/// Build a synthetic control for the treated region from the donor regions, matching it
+/// over the before days before policyday, and judge the gap afterward against placebo
+/// synthetic controls for each donor.  Donors should not have had the policy themselves,
+/// and those missing days the treated region has are left out.
+/// Pass smoothed data, so that the match is to the trend rather than the noise.  Returns
+/// None if there aren't enough days before the policy that every region has.
+pub fn syntheticcontrol(
+    treated: &HashMap<i32, f64>,
+    donors: &HashMap<String, HashMap<i32, f64>>,
+    policyday: i32,
+    before: i32,
+) -> Option<SyntheticControl> {
+    let window: Vec<&i32> = treated.keys().filter(|day| **day >= policyday - before).collect();
+    let mut donorlist: Vec<(&str, &HashMap<i32, f64>)> = donors
+        .iter()
+        .filter(|(_, hm)| window.iter().all(|day| hm.contains_key(day)))
+        .map(|(k, v)| (k.as_str(), v))
+        .collect();
+    donorlist.sort_by(|a, b| a.0.cmp(b.0));
+    let (weights, synthetic, rmspe_before, rmspe_after) = fitsynthetic(treated, &donorlist, policyday, before)?;
+
+    let ratio = |after: f64, before: f64| if before > 0.0 { after / before } else { f64::INFINITY };
+    let treatedratio = ratio(rmspe_after, rmspe_before);
+    let mut asextreme = 1;
+    let mut placebos = 1;
+    for (i, (_, placebo)) in donorlist.iter().enumerate() {
+        let mut others = donorlist.clone();
+        others.remove(i);
+        if let Some((_, _, before, after)) = fitsynthetic(placebo, &others, policyday, before) {
+            placebos += 1;
+            if ratio(after, before) >= treatedratio {
+                asextreme += 1;
+            }
+        }
+    }
+
+    let gaps: Vec<f64> = synthetic.iter().filter(|(day, _)| **day >= policyday).map(|(day, s)| treated[day] - s).collect();
+    let mut weights: Vec<(String, f64)> = donorlist
+        .iter()
+        .zip(weights)
+        .filter(|(_, w)| *w > 0.001)
+        .map(|((name, _), w)| (String::from(*name), w))
+        .collect();
+    weights.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    Some(SyntheticControl {
+        weights,
+        synthetic,
+        rmspe_before,
+        rmspe_after,
+        gap: gaps.iter().sum::<f64>() / gaps.len().max(1) as f64,
+        placebo_p: asextreme as f64 / placebos as f64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(actual: f64, expected: f64, tolerance: f64) {
+        assert!((actual - expected).abs() < tolerance, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn invert_matrices() {
+        let inverse = invert(&[vec![4.0, 7.0], vec![2.0, 6.0]]).unwrap();
+        let expected = [[0.6, -0.7], [-0.2, 0.4]];
+        for i in 0..2 {
+            for j in 0..2 {
+                assert_near(inverse[i][j], expected[i][j], 1e-12);
+            }
+        }
+        // The first pivot is zero, so rows have to be swapped
+        let inverse = invert(&[vec![0.0, 1.0], vec![1.0, 0.0]]).unwrap();
+        assert_eq!(inverse, vec![vec![0.0, 1.0], vec![1.0, 0.0]]);
+        assert!(invert(&[vec![1.0, 2.0], vec![2.0, 4.0]]).is_none());
+    }
+
+    #[test]
+    fn ols_exact_line() {
+        // y = 1 + 2x exactly, so no uncertainty
+        let x: Vec<Vec<f64>> = (0..10).map(|i| vec![1.0, i as f64]).collect();
+        let y: Vec<f64> = (0..10).map(|i| 1.0 + 2.0 * i as f64).collect();
+        let (beta, cov) = ols(&x, &y).unwrap();
+        assert_near(beta[0], 1.0, 1e-9);
+        assert_near(beta[1], 2.0, 1e-9);
+        assert!(cov.iter().flatten().all(|c| c.abs() < 1e-12));
+        // No more rows than coefficients, or collinear columns, give no fit
+        assert!(ols(&x[..2], &y[..2]).is_none());
+        let collinear: Vec<Vec<f64>> = (0..10).map(|i| vec![i as f64, 2.0 * i as f64]).collect();
+        assert!(ols(&collinear, &y).is_none());
+    }
+
+    /// 5 + 0.2t before day 200, then 3 higher and rising 0.5 a day less, plus noise that
+    /// alternates in sign
+    fn broken_line(noise: f64) -> HashMap<i32, f64> {
+        (150..260)
+            .map(|day| {
+                let t = (day - 200) as f64;
+                let post = if day >= 200 { 1.0 } else { 0.0 };
+                let sign = if day % 2 == 0 { 1.0 } else { -1.0 };
+                (day, 5.0 + 0.2 * t + post * (3.0 - 0.5 * t) + sign * noise)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn segmented_recovers_break() {
+        let fit = segmented(&broken_line(0.0), 200, 42, 56).unwrap();
+        assert_near(fit.level.value, 3.0, 1e-9);
+        assert_near(fit.slope.value, -0.5, 1e-9);
+        assert_near(fit.fitted[&210], 5.0 + 2.0 + 3.0 - 5.0, 1e-9);
+        // The counterfactual continues the trend from before
+        assert_near(fit.counterfactual[&210], 5.0 + 2.0, 1e-9);
+        assert_eq!(fit.fitted.len(), 98);
+        assert_eq!(fit.counterfactual.len(), 56);
+
+        // With noise, the estimates are close and their intervals cover the truth
+        let fit = segmented(&broken_line(1.0), 200, 42, 56).unwrap();
+        assert_near(fit.level.value, 3.0, 0.5);
+        assert_near(fit.slope.value, -0.5, 0.05);
+        assert!(fit.level.lower < 3.0 && 3.0 < fit.level.upper, "{:?}", fit.level);
+        assert!(fit.slope.lower < -0.5 && -0.5 < fit.slope.upper, "{:?}", fit.slope);
+    }
+
+    #[test]
+    fn segmented_needs_both_sides() {
+        let hm: HashMap<i32, f64> = broken_line(0.0).into_iter().filter(|(day, _)| *day < 202).collect();
+        assert!(segmented(&hm, 200, 42, 56).is_none());
+    }
+
+    #[test]
+    fn diffindiff_removes_shared_trend() {
+        // A comparison region with the same pre-policy trend and no break
+        let treated = broken_line(0.0);
+        let comparison: HashMap<i32, f64> = (150..260).map(|day| (day, 1.0 + 0.2 * (day - 200) as f64)).collect();
+        let fit = diffindiff(&treated, &comparison, 200, 42, 56).unwrap();
+        assert_near(fit.level.value, 3.0, 1e-9);
+        assert_near(fit.slope.value, -0.5, 1e-9);
+    }
+
+    #[test]
+    fn projectsimplex_onto_simplex() {
+        assert_eq!(projectsimplex(&[0.25, 0.75]), vec![0.25, 0.75]);
+        assert_eq!(projectsimplex(&[2.0, 0.0]), vec![1.0, 0.0]);
+        let projected = projectsimplex(&[0.6, 0.6, -1.0]);
+        assert_near(projected[0], 0.5, 1e-12);
+        assert_near(projected[1], 0.5, 1e-12);
+        assert_eq!(projected[2], 0.0);
+        let projected = projectsimplex(&[0.1, 0.2, 0.3]);
+        assert_near(projected.iter().sum(), 1.0, 1e-12);
+        assert!(projected.iter().all(|x| *x >= 0.0));
+    }
+
+    /// Three irregular donor series over days 100 through 159
+    fn donors() -> HashMap<String, HashMap<i32, f64>> {
+        let donor = |f: &dyn Fn(i32) -> f64| (0..60).map(|i| (100 + i, f(i))).collect::<HashMap<i32, f64>>();
+        vec![
+            (String::from("a"), donor(&|i| ((i * i + 3 * i) % 17) as f64)),
+            (String::from("b"), donor(&|i| 5.0 + ((7 * i + 2 * i * i) % 13) as f64)),
+            (String::from("c"), donor(&|i| 10.0 + ((i * i * i) % 11) as f64)),
+        ]
+        .into_iter()
+        .collect()
+    }
+
+    #[test]
+    fn simplexweights_convex_combination() {
+        let donors = donors();
+        let matrix: Vec<Vec<f64>> = (100..160).map(|day| ["a", "b", "c"].iter().map(|d| donors[*d][&day]).collect()).collect();
+        let target: Vec<f64> = matrix.iter().map(|row| 0.3 * row[0] + 0.7 * row[1]).collect();
+        let weights = simplexweights(&matrix, &target);
+        assert_near(weights[0], 0.3, 1e-4);
+        assert_near(weights[1], 0.7, 1e-4);
+        assert_near(weights[2], 0.0, 1e-4);
+    }
+
+    #[test]
+    fn syntheticcontrol_recovers_donors() {
+        // The treated region is 0.3 a + 0.7 b until day 142, then 3 lower
+        let donors = donors();
+        let treated: HashMap<i32, f64> = (100..160)
+            .map(|day| {
+                let effect = if day >= 142 { -3.0 } else { 0.0 };
+                (day, 0.3 * donors["a"][&day] + 0.7 * donors["b"][&day] + effect)
+            })
+            .collect();
+        let control = syntheticcontrol(&treated, &donors, 142, 42).unwrap();
+        assert_eq!(control.weights.len(), 2, "{:?}", control.weights);
+        assert_eq!(control.weights[0].0, "b");
+        assert_near(control.weights[0].1, 0.7, 1e-3);
+        assert_eq!(control.weights[1].0, "a");
+        assert_near(control.weights[1].1, 0.3, 1e-3);
+        assert!(control.rmspe_before < 1e-3);
+        assert_near(control.rmspe_after, 3.0, 1e-2);
+        assert_near(control.gap, -3.0, 1e-2);
+        // No placebo fits its own pre-period as well, so the treated region is the most extreme
+        assert_near(control.placebo_p, 0.25, 1e-12);
+    }
+}
@@ -0,0 +1,186 @@
+/* Local data files
+
+Copyright (c) 2020 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+ */
+
+use chrono::NaiveDate;
+use covid19db::dateutil::*;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Parse a YYYY-MM-DD date from a CSV file into a day number
+fn parse_day(path: &str, date: &str) -> i32 {
+    let nd = NaiveDate::parse_from_str(date.trim(), "%Y-%m-%d")
+        .unwrap_or_else(|e| panic!("{}: bad date {}: {}", path, date, e));
+    nd_to_day(&nd)
+}
+
+/// Parse a number from a CSV file
+fn parse_f64(path: &str, val: &str) -> f64 {
+    val.trim()
+        .parse()
+        .unwrap_or_else(|e| panic!("{}: bad number {}: {}", path, val, e))
+}
+
+/// Load Kansas county vaccination figures from a CSV file with "county", "date",
+/// "people_vaccinated" and "people_fully_vaccinated" columns, giving cumulative counts of
+/// people.  Returns a HashMap of counties to a HashMap from date_julian to (people
+/// vaccinated, people fully vaccinated).  If the file does not exist, there is no data.
+pub fn load_county_vaccinations(path: &str) -> HashMap<String, HashMap<i32, (f64, f64)>> {
+    let mut hm = HashMap::new();
+    if !Path::new(path).exists() {
+        return hm;
+    }
+    let mut rdr = csv::Reader::from_path(path).unwrap();
+    let headers = rdr.headers().unwrap().clone();
+    let col = |name: &str| {
+        headers
+            .iter()
+            .position(|h| h.trim() == name)
+            .unwrap_or_else(|| panic!("{} has no {} column", path, name))
+    };
+    let (countyidx, dateidx, vaccidx, fullidx) = (
+        col("county"),
+        col("date"),
+        col("people_vaccinated"),
+        col("people_fully_vaccinated"),
+    );
+    for result in rdr.records() {
+        let record = result.unwrap();
+        hm.entry(String::from(record[countyidx].trim()))
+            .or_insert_with(HashMap::new)
+            .insert(
+                parse_day(path, &record[dateidx]),
+                (parse_f64(path, &record[vaccidx]), parse_f64(path, &record[fullidx])),
+            );
+    }
+    hm
+}
+
+/// Population figures to use in place of those in the database, such as the census estimates
+/// we are required to use.  Regions are identified as "county:Harvey" for a Kansas county,
+/// "state:Kansas" for a US state, or "country:US" for a country by its code.
+#[derive(Debug, Default)]
+pub struct PopulationOverrides {
+    /// (region, population, vintage)
+    pub entries: Vec<(String, f64, String)>,
+}
+
+impl PopulationOverrides {
+    /// The overrides for regions of the given kind ("county", "state", or "country"), as
+    /// (name, population)
+    pub fn of_kind<'a>(&'a self, kind: &'a str) -> impl Iterator<Item = (&'a str, f64)> + 'a {
+        self.entries.iter().filter_map(move |(region, pop, _)| {
+            let mut parts = region.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(k), Some(name)) if k == kind => Some((name, *pop)),
+                _ => None,
+            }
+        })
+    }
+
+    /// The population to use for a region, such as "county:Harvey", if it is overridden
+    pub fn get(&self, region: &str) -> Option<f64> {
+        self.entries.iter().find(|(r, _, _)| r == region).map(|(_, pop, _)| *pop)
+    }
+
+    /// A note on the vintages of the overrides, for chart subtitles, if there are any
+    pub fn describe(&self) -> Option<String> {
+        let mut vintages: Vec<&str> = self.entries.iter().map(|(_, _, v)| v.as_str()).collect();
+        if vintages.is_empty() {
+            return None;
+        }
+        vintages.sort_unstable();
+        vintages.dedup();
+        Some(format!("Population: {} estimates where available", vintages.join(", ")))
+    }
+}
+
+/// Load population overrides from a CSV file with "region", "population" and "vintage"
+/// columns.  If the file does not exist, nothing is overridden.
+pub fn load_populations(path: &str) -> PopulationOverrides {
+    let mut pops = PopulationOverrides::default();
+    if !Path::new(path).exists() {
+        return pops;
+    }
+    let mut rdr = csv::Reader::from_path(path).unwrap();
+    let headers = rdr.headers().unwrap().clone();
+    let col = |name: &str| {
+        headers
+            .iter()
+            .position(|h| h.trim() == name)
+            .unwrap_or_else(|| panic!("{} has no {} column", path, name))
+    };
+    let (regionidx, popidx, vintageidx) = (col("region"), col("population"), col("vintage"));
+    for result in rdr.records() {
+        let record = result.unwrap();
+        let region = String::from(record[regionidx].trim());
+        if !(region.starts_with("county:") || region.starts_with("state:") || region.starts_with("country:")) {
+            panic!("{}: unknown kind of region {}", path, region);
+        }
+        pops.entries.push((
+            region,
+            parse_f64(path, &record[popidx]),
+            String::from(record[vintageidx].trim()),
+        ));
+    }
+    pops
+}
+
+/// A policy whose effect we estimate, such as a mask ordinance
+#[derive(Debug, Clone)]
+pub struct Policy {
+    pub name: String,
+    /// The county or group of counties it applies to
+    pub region: String,
+    /// The day it took effect
+    pub date: i32,
+    /// A county or group without the policy to compare against, if any
+    pub comparison: Option<String>,
+}
+
+/// Load policies from a CSV file with "name", "region", "date" and "comparison" columns;
+/// comparison may be left empty.  If the file does not exist, there are none.
+pub fn load_policies(path: &str) -> Vec<Policy> {
+    let mut policies = Vec::new();
+    if !Path::new(path).exists() {
+        return policies;
+    }
+    let mut rdr = csv::Reader::from_path(path).unwrap();
+    let headers = rdr.headers().unwrap().clone();
+    let col = |name: &str| {
+        headers
+            .iter()
+            .position(|h| h.trim() == name)
+            .unwrap_or_else(|| panic!("{} has no {} column", path, name))
+    };
+    let (nameidx, regionidx, dateidx, comparisonidx) = (col("name"), col("region"), col("date"), col("comparison"));
+    for result in rdr.records() {
+        let record = result.unwrap();
+        let comparison = record[comparisonidx].trim();
+        policies.push(Policy {
+            name: String::from(record[nameidx].trim()),
+            region: String::from(record[regionidx].trim()),
+            date: parse_day(path, &record[dateidx]),
+            comparison: match comparison.is_empty() {
+                true => None,
+                false => Some(String::from(comparison)),
+            },
+        });
+    }
+    policies
+}
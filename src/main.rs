@@ -18,264 +18,860 @@ Copyright (c) 2019-2020 John Goerzen
 
 use chrono::Local;
 use covid19db::dateutil::*;
+use futures::stream::{self, StreamExt};
 use sqlx::sqlite::SqlitePool;
-use std::collections::HashMap;
-use std::cmp::max;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::error::Error;
 use std::ffi::OsString;
+use std::fs;
 use std::fs::File;
+use std::hash::Hash;
 use std::path::Path;
 
+use smoothing::{Partial, Smoother, Smoothing};
+
 mod analysis;
+mod cache;
 mod charts;
 mod counties;
 mod db;
+mod forecast;
+mod intervention;
+mod localdata;
+mod page;
+mod site;
+mod smoothing;
+#[cfg(test)]
+mod testutil;
 
-/// Returns the first positional argument sent to this process. If there are no
-/// positional arguments, then this returns an error.
+/// Returns the nth positional argument sent to this process, not counting
+/// --flags. If there is no such argument, then this returns an error.
 fn get_nth_arg(arg: usize) -> Result<OsString, Box<dyn Error>> {
-    match env::args_os().nth(arg) {
+    match env::args_os()
+        .filter(|x| !x.to_string_lossy().starts_with("--"))
+        .nth(arg)
+    {
         None => Err(From::from("expected 1 argument, but got none")),
         Some(file_path) => Ok(file_path),
     }
 }
 
-async fn write_incidence_100k(pool: &SqlitePool, bightml: &mut File, first_date: i32, last_date: i32) {
-    let mut nytbycounty100k = db::getcountydata_100k_nytcounties(
-        &pool,
-        "cases_new",
-        first_date,
-        last_date,
-    )
-    .await;
+/// Returns true if the given --flag was sent to this process.
+fn has_flag(flag: &str) -> bool {
+    env::args_os().any(|x| x == flag)
+}
 
-    let mut nytbycounty100k_sum = nytbycounty100k.clone();
-    for item in nytbycounty100k_sum.values_mut() {
-        *item = analysis::calcsimplesum(item, 14, true);
+/// Returns the value of the given --flag=value sent to this process, if any.
+fn flag_value(flag: &str) -> Option<String> {
+    let prefix = format!("{}=", flag);
+    env::args()
+        .find(|x| x.starts_with(&prefix))
+        .map(|x| String::from(&x[prefix.len()..]))
+}
+
+/// The last day to chart, given today: two days ago, since the sources take a day or two
+/// to fill in, but no later than the last day in the database.  Charts are keyed on this
+/// in the build cache, so an unchanged database stays fresh as the calendar moves on.
+fn chart_last_date(today: i32, db_last_date: Option<i32>) -> i32 {
+    match db_last_date {
+        Some(db_last_date) => db_last_date.min(today - 2),
+        None => today - 2,
     }
+}
 
-    // 268 total cases on 2020-08-21; 200 on 2020-08-08, per NYT spreadsheet, so 68 cases
-    let rate_20200822 = 100000f64 * 68.0 / 34429.0;
-    assert!(rate_20200822 - 0.0000001 < *nytbycounty100k_sum.get("Harvey").unwrap().get(&ymd_to_day(2020, 8, 21)).unwrap());
-    assert!(rate_20200822 + 0.0000001 > *nytbycounty100k_sum.get("Harvey").unwrap().get(&ymd_to_day(2020, 8, 21)).unwrap());
+/// The states and countries we chart from cdataset, as (label, dataset, where_clause).
+/// The first six are the Central USA states and the USA as a whole.
+const JHU_REGIONS: &[(&str, &str, &str)] = &[
+    ("Kansas", "jhu/daily", "province = 'Kansas' and country_code = 'US' and location_type = 'total-province'"),
+    ("Missouri", "jhu/daily", "province = 'Missouri' and country_code = 'US' and location_type = 'total-province'"),
+    ("Nebraska", "jhu/daily", "province = 'Nebraska' and country_code = 'US' and location_type = 'total-province'"),
+    ("Colorado", "jhu/daily", "province = 'Colorado' and country_code = 'US' and location_type = 'total-province'"),
+    ("Oklahoma", "jhu/daily", "province = 'Oklahoma' and country_code = 'US' and location_type = 'total-province'"),
+    ("USA", "jhu/series", "province = '' and country_code = 'US' and location_type = 'total-country'"),
+    ("Canada", "jhu/series", "province = '' and country_code = 'CA' and location_type = 'total-country'"),
+    ("United Kingdom", "jhu/series", "province = '' and country_code = 'GB' and location_type = 'total-country'"),
+    ("France", "jhu/series", "province = '' and country_code = 'FR' and location_type = 'total-country'"),
+    ("Taiwan", "jhu/series", "province = '' and country_code = 'TW' and location_type = 'total-country'"),
+];
 
-    charts::writecounties_100k(
-        "counties-100k-sum-nyt",
-        bightml,
-        "14-day New COVID-19 Cases (NYT)",
-        "14-day sum of new cases per 100,000 pop.",
-        &vec!["Marion", "Harvey", "Sedgwick", "McPherson"],
-        &nytbycounty100k_sum,
-        first_date,
-        last_date,
+/// The smoothing for most charts, unless charts.toml says otherwise
+const MA7: Smoothing = Smoothing::Trailing { window: 7, partial: Partial::Shrink };
+
+/// The charts that smooth their data as charts.toml says, and so take a smoothing option
+const SMOOTHED_CHARTS: &[&str] = &[
+    "counties-100k-sum-nyt",
+    "counties-100k-nyt",
+    "counties-rank-nyt",
+    "groups-100k-nyt",
+    "centralusa-100k",
+    "global-100k",
+    "counties-aligned-100k-nyt",
+    "counties-since-mask-nyt",
+    "tests-per-1000",
+    "classes-cases-100k-nyt",
+    "classes-deaths-100k-nyt",
+    "leadlag-counties-nyt",
+    "leadlag-states-jhu",
+    "policy-fitted-nyt",
+    "synthetic-control-nyt",
+];
+
+/// Label for the y axis of a chart of what, smoothed as given
+fn smoothedaxis(smoothing: &Smoothing, what: &str) -> String {
+    match smoothing {
+        Smoothing::None => format!("Daily {}", what),
+        _ => format!("{} of {}", smoothing.describe(), what),
+    }
+}
+
+/// Smooth each of the series in hm
+fn smoothall<K: Clone + Eq + Hash>(hm: &HashMap<K, HashMap<i32, f64>>, smoothing: &Smoothing) -> HashMap<K, HashMap<i32, f64>> {
+    hm.iter().map(|(key, data)| (key.clone(), smoothing.smooth(data))).collect()
+}
+
+/// Poisson confidence interval around a county's smoothed rate per 100k pop., given its
+/// counts and population, if it is smoothed by a trailing mean or sum
+fn countyinterval(
+    smoothing: &Smoothing,
+    smoothed: &HashMap<i32, f64>,
+    counts: &HashMap<i32, f64>,
+    population: f64,
+) -> Option<analysis::Interval> {
+    match *smoothing {
+        Smoothing::Trailing { window, partial } => {
+            Some(analysis::poissoninterval(smoothed, counts, population, window, true, partial))
+        }
+        Smoothing::Sum { window, partial } => Some(analysis::poissoninterval(smoothed, counts, population, window, false, partial)),
+        _ => None,
+    }
+}
+
+/// Shrink each county's smoothed rate per 100k pop. toward the statewide rate by empirical
+/// Bayes, day by day.  Only the counties in population are shrunk; anything else in
+/// bycounty, such as groups, is left as is.
+fn shrinkcounties(
+    bycounty: &HashMap<String, HashMap<i32, f64>>,
+    population: &HashMap<String, f64>,
+    smoothing: &Smoothing,
+) -> HashMap<String, HashMap<i32, f64>> {
+    let days = match smoothing {
+        Smoothing::Sum { .. } => 1.0,
+        _ => smoothing.days() as f64,
+    };
+    let mut ret = bycounty.clone();
+    let alldays: HashSet<i32> = bycounty
+        .iter()
+        .filter(|(county, _)| population.contains_key(*county))
+        .flat_map(|(_, data)| data.keys().copied())
+        .collect();
+    for day in alldays {
+        // The cases behind each rate, and the exposure in 100k person-days
+        let regions: HashMap<&String, (f64, f64)> = bycounty
+            .iter()
+            .filter_map(|(county, data)| {
+                let exposure = population.get(county)? / 100000.0 * days;
+                Some((county, (data.get(&day)? * exposure, exposure)))
+            })
+            .collect();
+        for (county, rate) in analysis::ebshrink(&regions) {
+            ret.get_mut(county).unwrap().insert(day, rate);
+        }
+    }
+    ret
+}
+
+/// Options for a chart of rates per population, noting the vintage of any population
+/// estimates used in place of those in the database.
+fn opts_100k(pops: &localdata::PopulationOverrides) -> charts::ChartOptions {
+    charts::ChartOptions::new().subtitle(pops.describe())
+}
+
+/// Fetch field per 100k pop. from cdataset for each (label, dataset, where_clause).  The
+/// queries run concurrently, as many at once as the pool has connections.  Returns a
+/// HashMap from label to data.
+async fn getjhudata_100k(
+    pool: &SqlitePool,
+    pops: &localdata::PopulationOverrides,
+    regions: &[(&'static str, &'static str, &'static str)],
+    field: &'static str,
+    first_date: i32,
+    last_date: i32,
+) -> HashMap<&'static str, HashMap<i32, f64>> {
+    stream::iter(regions)
+        .map(|&(label, dataset, where_clause)| async move {
+            let raw = db::getgeneralmaskdata_100k(
+                pool,
+                pops,
+                dataset,
+                field,
+                where_clause,
+                first_date,
+                last_date,
+            )
+            .await;
+            (label, raw)
+        })
+        .buffer_unordered(pool.max_size() as usize)
+        .collect()
+        .await
+}
+
+/// Spread each series' batch reports back over the days they cover; see
+/// analysis::redistribute.  Only for daily figures, not cumulative ones.
+fn debatchall<K>(data: &mut HashMap<K, HashMap<i32, f64>>) {
+    for item in data.values_mut() {
+        *item = analysis::debatch(item);
+    }
+}
+
+/// Fetch a daily field, such as cases_new, per 100k pop. for each Kansas county and each
+/// group, with batch reports spread back over the days they cover.  Groups can be used
+/// anywhere a county can.
+async fn getnytdaily_100k(
+    pool: &SqlitePool,
+    pops: &localdata::PopulationOverrides,
+    field: &str,
+    groups: &[counties::CountyGroup],
+    first_date: i32,
+    last_date: i32,
+) -> HashMap<String, HashMap<i32, f64>> {
+    let (mut bycounty, bygroup) = futures::join!(
+        db::getcountydata_100k_nytcounties(pool, pops, field, first_date, last_date),
+        db::getgroupdata_100k_nytcounties(pool, pops, field, groups, first_date, last_date),
     );
+    bycounty.extend(bygroup);
+    debatchall(&mut bycounty);
+    bycounty
+}
 
-    for item in nytbycounty100k.values_mut() {
-        *item = analysis::calcsimplema(item, 7);
+async fn write_incidence_100k(
+    pool: &SqlitePool,
+    cache: &mut cache::BuildCache,
+    out: &mut charts::Output,
+    pops: &localdata::PopulationOverrides,
+    groups: &[counties::CountyGroup],
+    first_date: i32,
+    last_date: i32,
+) {
+    let charts = [
+        "counties-100k-sum-nyt",
+        "counties-100k-nyt",
+        "counties-rank-nyt",
+        "groups-100k-nyt",
+        "centralusa-100k",
+        "global-100k",
+    ];
+    let definition = format!("incidence_100k {} {} {:?} {:?}", first_date, last_date, groups, pops);
+    if cache.reuse(&charts, &definition, out) {
+        return;
     }
 
+    let (mut nytbycounty100k, nytbygroup100k, mut deltconf, mut nytbycounty, countypop) = futures::join!(
+        db::getcountydata_100k_nytcounties(pool, pops, "cases_new", first_date, last_date),
+        db::getgroupdata_100k_nytcounties(pool, pops, "cases_new", groups, first_date, last_date),
+        getjhudata_100k(
+            pool,
+            pops,
+            JHU_REGIONS,
+            "delta_confirmed",
+            first_date,
+            last_date,
+        ),
+        db::getcountydata_nytcounties(pool, "cases_new", first_date, last_date),
+        db::getcountypopulation_nytcounties(pool, pops),
+    );
+
+    // Groups can be used anywhere a county can
+    nytbycounty100k.extend(nytbygroup100k);
+
+    // Check our windows against the NYT spreadsheet, which has the data as reported
+    let harvey_pop = pops.get("county:Harvey").unwrap_or(34429.0);
+    let harvey_sum = smoothing::RollingSum { window: 14, partial: Partial::Shrink }.smooth(nytbycounty100k.get("Harvey").unwrap());
+    let harvey_ma = smoothing::TrailingMean { window: 7, partial: Partial::Shrink }.smooth(nytbycounty100k.get("Harvey").unwrap());
+
+    // 268 total cases on 2020-08-21; 200 on 2020-08-08, per NYT spreadsheet, so 68 cases
+    let rate_20200822 = 100000f64 * 68.0 / harvey_pop;
+    assert!(rate_20200822 - 0.0000001 < *harvey_sum.get(&ymd_to_day(2020, 8, 21)).unwrap());
+    assert!(rate_20200822 + 0.0000001 > *harvey_sum.get(&ymd_to_day(2020, 8, 21)).unwrap());
+
     // 268 total cases on 2020-08-21; 224 on 2020-08-15 and there were 224 on 2020-08-14 as well.
     // So 44 new cases over that 7-day period.
-    let rate_20200822 = 100000f64 * (44.0 / 7.0) / 34429.0;
-    assert!(rate_20200822 - 0.0000001 < *nytbycounty100k.get("Harvey").unwrap().get(&ymd_to_day(2020, 8, 21)).unwrap());
-    assert!(rate_20200822 + 0.0000001 > *nytbycounty100k.get("Harvey").unwrap().get(&ymd_to_day(2020, 8, 21)).unwrap());
+    let rate_20200822 = 100000f64 * (44.0 / 7.0) / harvey_pop;
+    assert!(rate_20200822 - 0.0000001 < *harvey_ma.get(&ymd_to_day(2020, 8, 21)).unwrap());
+    assert!(rate_20200822 + 0.0000001 > *harvey_ma.get(&ymd_to_day(2020, 8, 21)).unwrap());
 
+    // Kansas counties reported only on certain weekdays for much of the pandemic.  Spread
+    // those batches back over the days they cover, so windows that don't cover full weeks
+    // aren't thrown off.
+    debatchall(&mut nytbycounty100k);
+    debatchall(&mut nytbycounty);
+    debatchall(&mut deltconf);
 
-    charts::writecounties_100k(
+    // Small counties' rates bounce around with just a few cases, so we show how much, or
+    // shrink them toward the statewide rate if the chart is configured to
+    let counties = ["Marion", "Harvey", "Sedgwick", "McPherson"];
+    let rates = |opts: &charts::ChartOptions| match opts.rate {
+        charts::Rate::Observed => smoothall(&nytbycounty100k, &opts.smoothing),
+        charts::Rate::Shrunk => shrinkcounties(&smoothall(&nytbycounty100k, &opts.smoothing), &countypop, &opts.smoothing),
+    };
+    let intervals = |opts: &charts::ChartOptions, smoothed: &HashMap<String, HashMap<i32, f64>>| -> HashMap<&str, analysis::Interval> {
+        if opts.rate == charts::Rate::Shrunk {
+            return HashMap::new();
+        }
+        counties
+            .iter()
+            .filter_map(|county| {
+                countyinterval(&opts.smoothing, &smoothed[*county], &nytbycounty[*county], countypop[*county]).map(|ci| (*county, ci))
+            })
+            .collect()
+    };
+
+    let opts = out.config.apply(
+        "counties-100k-sum-nyt",
+        &opts_100k(pops).smoothing(Smoothing::Sum { window: 14, partial: Partial::Shrink }),
+    );
+    let smoothed = rates(&opts);
+    let cis = intervals(&opts, &smoothed);
+    charts::write_banded(
+        "counties-100k-sum-nyt",
+        out,
+        "14-day New COVID-19 Cases (NYT)",
+        &smoothedaxis(&opts.smoothing, "new cases per 100,000 pop., with 95% confidence interval"),
+        counties.iter().map(|county| (*county, &smoothed[*county], cis.get(county))).collect(),
+        first_date,
+        last_date,
+        &opts,
+    );
+
+    let opts = out.config.apply("counties-100k-nyt", &opts_100k(pops).smoothing(MA7));
+    let smoothed = rates(&opts);
+    let cis = intervals(&opts, &smoothed);
+    let forecasts: HashMap<&str, Option<analysis::Interval>> = counties
+        .iter()
+        .map(|county| (*county, forecast::latest(smoothed.get(*county).unwrap())))
+        .collect();
+    charts::write_forecasts(
         "counties-100k-nyt",
-        bightml,
+        out,
         "New COVID-19 cases in Selected Counties, Kansas (NYT)",
-        "7-day moving avg of new cases per 100,000 pop.",
-        &vec!["Marion", "Harvey", "Sedgwick", "McPherson"],
-        &nytbycounty100k,
+        &smoothedaxis(&opts.smoothing, "new cases per 100,000 pop., with 14-day forecast"),
+        counties
+            .iter()
+            .map(|county| (*county, smoothed.get(*county).unwrap(), cis.get(county), forecasts[county].as_ref()))
+            .collect(),
         first_date,
         last_date,
+        &opts,
+    );
+
+    // Every county, ranked by its rate as of the last day
+    let opts = out.config.apply(
+        "counties-rank-nyt",
+        &opts_100k(pops).smoothing(Smoothing::Sum { window: 14, partial: Partial::Shrink }).rate(charts::Rate::Shrunk),
+    );
+    let observed = smoothall(&nytbycounty100k, &opts.smoothing);
+    let shrunk = shrinkcounties(&observed, &countypop, &opts.smoothing);
+    // The latest day any county has data for; counties without data that day are left out
+    let asof = countypop
+        .keys()
+        .filter_map(|county| observed.get(county)?.keys().filter(|day| **day <= last_date).max())
+        .max()
+        .copied()
+        .expect("No county data to rank");
+    let mut ranked: Vec<(&String, f64, f64, f64)> = countypop
+        .iter()
+        .filter_map(|(county, pop)| Some((county, *pop, *observed.get(county)?.get(&asof)?, *shrunk.get(county)?.get(&asof)?)))
+        .collect();
+    ranked.sort_by(|a, b| {
+        let (a, b) = match opts.rate {
+            charts::Rate::Observed => (a.2, b.2),
+            charts::Rate::Shrunk => (a.3, b.3),
+        };
+        b.partial_cmp(&a).unwrap()
+    });
+    charts::write_table(
+        "counties-rank-nyt",
+        out,
+        &format!(
+            "Kansas counties ranked by {} {} of new cases per 100,000 pop. as of {} (NYT)",
+            match opts.rate {
+                charts::Rate::Observed => "observed",
+                charts::Rate::Shrunk => "shrunk",
+            },
+            opts.smoothing.describe(),
+            day_to_nd(asof)
+        ),
+        &["Rank", "County", "Population", "Observed rate", "Shrunk rate"],
+        ranked
+            .into_iter()
+            .zip(1..)
+            .map(|((county, pop, observed, shrunk), rank)| {
+                vec![
+                    rank.to_string(),
+                    county.clone(),
+                    format!("{:.0}", pop),
+                    format!("{:.1}", observed),
+                    format!("{:.1}", shrunk),
+                ]
+            })
+            .collect(),
     );
 
-    let deltconfks = db::getgeneralmaskdata_100k(
-        &pool,
-        "jhu/daily",
-        "delta_confirmed",
-        "province = 'Kansas' and country_code = 'US' and location_type = 'total-province'",
+    let opts = out.config.apply("groups-100k-nyt", &opts_100k(pops).smoothing(MA7));
+    charts::writecounties_100k(
+        "groups-100k-nyt",
+        out,
+        "New COVID-19 cases in County Groups, Kansas (NYT)",
+        &smoothedaxis(&opts.smoothing, "new cases per 100,000 pop."),
+        &groups.iter().map(|g| g.name.as_str()).collect(),
+        &smoothall(&nytbycounty100k, &opts.smoothing),
         first_date,
         last_date,
-    )
-    .await;
-    let rate_20200820 = 100000f64 * (35907.0 - 35419.0) / 2913314.0;
-    //assert!(rate_20200820 + 0.0000001 > *deltconfks.get(&ymd_to_day(2020, 8, 20)).unwrap());
-    //assert!(rate_20200820 - 0.0000001 < *deltconfks.get(&ymd_to_day(2020, 8, 20)).unwrap());
-
+        &opts,
+    );
 
-    let deltconfks = analysis::calcsimplema(&deltconfks, 7);
+    let _rate_20200820 = 100000f64 * (35907.0 - 35419.0) / 2913314.0;
+    //assert!(_rate_20200820 + 0.0000001 > *deltconf["Kansas"].get(&ymd_to_day(2020, 8, 20)).unwrap());
+    //assert!(_rate_20200820 - 0.0000001 < *deltconf["Kansas"].get(&ymd_to_day(2020, 8, 20)).unwrap());
 
     // 35907 on 20200820; 32484 on 20200813; that day is included because the delta on 20200814 is nonzero
-    let rate_20200820 = 100000f64 * ((35907.0 - 32484.0) / 7.0) / 2913314.0;
-    //assert!(rate_20200820 + 0.0000001 > *deltconfks.get(&ymd_to_day(2020, 8, 20)).unwrap());
-    //assert!(rate_20200820 - 0.0000001 < *deltconfks.get(&ymd_to_day(2020, 8, 20)).unwrap());
-
-    let deltconfmo = db::getgeneralmaskdata_100k(
-        &pool,
-        "jhu/daily",
-        "delta_confirmed",
-        "province = 'Missouri' and country_code = 'US' and location_type = 'total-province'",
+    let _rate_20200820 = 100000f64 * ((35907.0 - 32484.0) / 7.0) / 2913314.0;
+    //assert!(_rate_20200820 + 0.0000001 > MA7.smooth(&deltconf["Kansas"]).get(&ymd_to_day(2020, 8, 20)).unwrap());
+    //assert!(_rate_20200820 - 0.0000001 < MA7.smooth(&deltconf["Kansas"]).get(&ymd_to_day(2020, 8, 20)).unwrap());
+
+    // 44023 from the graph on their website; on 9-1 it was showing 44036; on 9-2, back to 44023
+    let rate_20200820 = 100000f64 * 44023.0 / 332639102.0;
+    println!("{}, {}", rate_20200820, *deltconf["USA"].get(&ymd_to_day(2020, 8, 20)).unwrap());
+    // assert!(rate_20200820 + 0.0000001 > *deltconf["USA"].get(&ymd_to_day(2020, 8, 20)).unwrap());
+    // assert!(rate_20200820 - 0.0000001 < *deltconf["USA"].get(&ymd_to_day(2020, 8, 20)).unwrap());
+
+    let centralusa = ["Kansas", "Missouri", "Colorado", "Nebraska", "Oklahoma", "USA"];
+    let opts = out.config.apply("centralusa-100k", &opts_100k(pops).smoothing(MA7));
+    let smoothed = smoothall(&deltconf, &opts.smoothing);
+    let forecasts: HashMap<&str, Option<analysis::Interval>> = centralusa
+        .iter()
+        .map(|region| (*region, forecast::latest(&smoothed[region])))
+        .collect();
+    charts::write_forecasts(
+        "centralusa-100k",
+        out,
+        "New COVID-19 cases in Central USA (JHU)",
+        &smoothedaxis(&opts.smoothing, "new cases per 100,000 pop., with 14-day forecast"),
+        centralusa
+            .iter()
+            .map(|region| (*region, &smoothed[region], None, forecasts[region].as_ref()))
+            .collect(),
         first_date,
         last_date,
-    )
-    .await;
-    let deltconfmo = analysis::calcsimplema(&deltconfmo, 7);
-    let deltconfne = db::getgeneralmaskdata_100k(
-        &pool,
-        "jhu/daily",
-        "delta_confirmed",
-        "province = 'Nebraska' and country_code = 'US' and location_type = 'total-province'",
+        &opts,
+    );
+
+    let opts = out.config.apply("global-100k", &opts_100k(pops).smoothing(MA7).y_log(true));
+    let smoothed = smoothall(&deltconf, &opts.smoothing);
+    let sedgwick = opts.smoothing.smooth(nytbycounty100k.get("Sedgwick").unwrap());
+    charts::write_generic(
+        "global-100k",
+        out,
+        "New COVID-19 cases in Selected Regions (JHU + NYT where indicated)",
+        &smoothedaxis(&opts.smoothing, "new cases per 100,000 pop."),
+        vec![
+            ("Kansas", &smoothed["Kansas"]),
+            ("Sedgwick County (NYT)", &sedgwick),
+            ("USA", &smoothed["USA"]),
+            ("Canada", &smoothed["Canada"]),
+            ("United Kingdom", &smoothed["United Kingdom"]),
+            ("France", &smoothed["France"]),
+            ("Taiwan", &smoothed["Taiwan"]),
+        ],
         first_date,
         last_date,
-    )
-    .await;
-    let deltconfne = analysis::calcsimplema(&deltconfne, 7);
-    let deltconfco = db::getgeneralmaskdata_100k(
-        &pool,
-        "jhu/daily",
-        "delta_confirmed",
-        "province = 'Colorado' and country_code = 'US' and location_type = 'total-province'",
+        &opts,
+    );
+
+    cache.record(&charts, &definition, &out.config);
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn write_aligned_100k(
+    pool: &SqlitePool,
+    cache: &mut cache::BuildCache,
+    out: &mut charts::Output,
+    pops: &localdata::PopulationOverrides,
+    groups: &[counties::CountyGroup],
+    policies: &[localdata::Policy],
+    first_date: i32,
+    last_date: i32,
+) {
+    // Counties are aligned on policy dates only if there are policies to align on
+    let mut charts = vec!["counties-aligned-100k-nyt"];
+    if !policies.is_empty() {
+        charts.push("counties-since-mask-nyt");
+    }
+    let definition = format!("aligned_100k {} {} {:?} {:?} {:?}", first_date, last_date, groups, pops, policies);
+    if cache.reuse(&charts, &definition, out) {
+        return;
+    }
+
+    let nytbycounty100k = getnytdaily_100k(pool, pops, "cases_new", groups, first_date, last_date).await;
+
+    // Counties or groups that never reached the threshold are left off
+    let mut names = vec!["Marion", "Harvey", "Sedgwick", "McPherson"];
+    names.extend(groups.iter().map(|g| g.name.as_str()));
+    let opts = out.config.apply("counties-aligned-100k-nyt", &opts_100k(pops).smoothing(MA7));
+    let smoothed = smoothall(&nytbycounty100k, &opts.smoothing);
+    let series = names
+        .into_iter()
+        .filter_map(|name| {
+            let data = smoothed.get(name).unwrap();
+            analysis::firstcrossing(data, 1.0).map(|start| (name, data, start))
+        })
+        .collect();
+    charts::write_aligned(
+        "counties-aligned-100k-nyt",
+        out,
+        "New COVID-19 cases by Days Since Reaching 1 per 100,000, Kansas (NYT)",
+        &format!("Days since {} first reached 1 new case per 100,000 pop.", opts.smoothing.describe()),
+        &smoothedaxis(&opts.smoothing, "new cases per 100,000 pop."),
+        series,
+        0,
+        last_date,
+        &opts,
+    );
+
+    if !policies.is_empty() {
+        let opts = out.config.apply("counties-since-mask-nyt", &opts_100k(pops).smoothing(MA7));
+        let smoothed = smoothall(&nytbycounty100k, &opts.smoothing);
+        let labels: Vec<String> = policies.iter().map(|p| format!("{} ({})", p.region, p.name)).collect();
+        let series = policies
+            .iter()
+            .zip(labels.iter())
+            .map(|(policy, label)| {
+                let data = smoothed
+                    .get(&policy.region)
+                    .unwrap_or_else(|| panic!("policies.csv: {} is not a Kansas county or a county group", policy.region));
+                (label.as_str(), data, policy.date)
+            })
+            .collect();
+        charts::write_aligned(
+            "counties-since-mask-nyt",
+            out,
+            "New COVID-19 cases by Days Since Policy Took Effect, Kansas (NYT)",
+            "Days since policy took effect",
+            &smoothedaxis(&opts.smoothing, "new cases per 100,000 pop."),
+            series,
+            28,
+            last_date,
+            &opts,
+        );
+    }
+
+    cache.record(&charts, &definition, &out.config);
+}
+
+async fn write_cumulative(
+    pool: &SqlitePool,
+    cache: &mut cache::BuildCache,
+    out: &mut charts::Output,
+    pops: &localdata::PopulationOverrides,
+    groups: &[counties::CountyGroup],
+    first_date: i32,
+    last_date: i32,
+) {
+    let charts = [
+        "cumulative-counties-nyt",
+        "cumulative-deaths-counties-nyt",
+        "cumulative-global",
+        "cumulative-deaths-global",
+        "pct-confirmed",
+    ];
+    let definition = format!("cumulative {} {} {:?} {:?}", first_date, last_date, groups, pops);
+    if cache.reuse(&charts, &definition, out) {
+        return;
+    }
+
+    // cases and deaths in nytcounties are cumulative
+    let (mut cases, casegroups, mut deaths, deathgroups, jhucases, jhudeaths) = futures::join!(
+        db::getcountydata_100k_nytcounties(pool, pops, "cases", first_date, last_date),
+        db::getgroupdata_100k_nytcounties(pool, pops, "cases", groups, first_date, last_date),
+        db::getcountydata_100k_nytcounties(pool, pops, "deaths", first_date, last_date),
+        db::getgroupdata_100k_nytcounties(pool, pops, "deaths", groups, first_date, last_date),
+        getjhudata_100k(pool, pops, JHU_REGIONS, "absolute_confirmed", first_date, last_date),
+        getjhudata_100k(pool, pops, JHU_REGIONS, "absolute_deaths", first_date, last_date),
+    );
+    cases.extend(casegroups);
+    deaths.extend(deathgroups);
+
+    let mut names = vec!["Marion", "Harvey", "Sedgwick", "McPherson"];
+    names.extend(groups.iter().map(|g| g.name.as_str()));
+    charts::writecounties_100k(
+        "cumulative-counties-nyt",
+        out,
+        "Total COVID-19 Cases in Selected Counties, Kansas (NYT)",
+        "Total cases per 100,000 pop.",
+        &names,
+        &cases,
         first_date,
         last_date,
-    )
-    .await;
-    let deltconfco = analysis::calcsimplema(&deltconfco, 7);
-    let deltconfok = db::getgeneralmaskdata_100k(
-        &pool,
-        "jhu/daily",
-        "delta_confirmed",
-        "province = 'Oklahoma' and country_code = 'US' and location_type = 'total-province'",
+        &opts_100k(pops),
+    );
+    charts::writecounties_100k(
+        "cumulative-deaths-counties-nyt",
+        out,
+        "Total COVID-19 Deaths in Selected Counties, Kansas (NYT)",
+        "Total deaths per 100,000 pop.",
+        &names,
+        &deaths,
         first_date,
         last_date,
-    )
-    .await;
-    let deltconfok = analysis::calcsimplema(&deltconfok, 7);
-
-    let deltconfus = db::getgeneralmaskdata_100k(
-        &pool,
-        "jhu/series",
-        "delta_confirmed",
-        "province = '' and country_code = 'US' and location_type = 'total-country'",
+        &opts_100k(pops),
+    );
+    charts::write_generic(
+        "cumulative-global",
+        out,
+        "Total COVID-19 Cases in Selected Regions (JHU)",
+        "Total cases per 100,000 pop.",
+        JHU_REGIONS.iter().map(|(label, _, _)| (*label, &jhucases[label])).collect(),
         first_date,
         last_date,
-    )
-    .await;
-
-    // 44023 from the graph on their website; on 9-1 it was showing 44036; on 9-2, back to 44023
-    let rate_20200820 = 100000f64 * 44023.0 / 332639102.0;
-    println!("{}, {}", rate_20200820, *deltconfus.get(&ymd_to_day(2020, 8, 20)).unwrap());
-    // assert!(rate_20200820 + 0.0000001 > *deltconfus.get(&ymd_to_day(2020, 8, 20)).unwrap());
-    // assert!(rate_20200820 - 0.0000001 < *deltconfus.get(&ymd_to_day(2020, 8, 20)).unwrap());
-
-    let deltconfus = analysis::calcsimplema(&deltconfus, 7);
-
-    let deltconfcan = db::getgeneralmaskdata_100k(
-        &pool,
-        "jhu/series",
-        "delta_confirmed",
-        "province = '' and country_code = 'CA' and location_type = 'total-country'",
+        &opts_100k(pops),
+    );
+    charts::write_generic(
+        "cumulative-deaths-global",
+        out,
+        "Total COVID-19 Deaths in Selected Regions (JHU)",
+        "Total deaths per 100,000 pop.",
+        JHU_REGIONS.iter().map(|(label, _, _)| (*label, &jhudeaths[label])).collect(),
         first_date,
         last_date,
-    )
-    .await;
-    let deltconfcan = analysis::calcsimplema(&deltconfcan, 7);
+        &opts_100k(pops),
+    );
 
-    let deltconfgbr = db::getgeneralmaskdata_100k(
-        &pool,
-        "jhu/series",
-        "delta_confirmed",
-        "province = '' and country_code = 'GB' and location_type = 'total-country'",
+    // 1% of the population is 1,000 per 100,000
+    let topct = |hm: &HashMap<i32, f64>| -> HashMap<i32, f64> { hm.iter().map(|(k, v)| (*k, v / 1000.0)).collect() };
+    let mut pct: Vec<(&str, HashMap<i32, f64>)> = ["Marion", "Harvey", "Sedgwick", "McPherson"]
+        .iter()
+        .map(|county| (*county, topct(cases.get(*county).unwrap())))
+        .collect();
+    pct.push(("Kansas (JHU)", topct(&jhucases["Kansas"])));
+    pct.push(("USA (JHU)", topct(&jhucases["USA"])));
+    charts::write_generic(
+        "pct-confirmed",
+        out,
+        "Percent of Population With Confirmed COVID-19 (NYT + JHU where indicated)",
+        "% of population confirmed",
+        pct.iter().map(|(label, data)| (*label, data)).collect(),
         first_date,
         last_date,
-    )
-    .await;
-    let deltconfgbr = analysis::calcsimplema(&deltconfgbr, 7);
+        &opts_100k(pops),
+    );
+
+    cache.record(&charts, &definition, &out.config);
+}
+
+async fn write_weekday_diagnostic(
+    pool: &SqlitePool,
+    cache: &mut cache::BuildCache,
+    out: &mut charts::Output,
+    pops: &localdata::PopulationOverrides,
+    first_date: i32,
+    last_date: i32,
+) {
+    let charts = ["weekday-effect-nyt", "weekday-redistributed-nyt"];
+    let definition = format!("weekday {} {} {:?}", first_date, last_date, pops);
+    if cache.reuse(&charts, &definition, out) {
+        return;
+    }
+
+    let nytbycounty100k = db::getcountydata_100k_nytcounties(pool, pops, "cases_new", first_date, last_date).await;
+    let counties = ["Marion", "Harvey", "Sedgwick", "McPherson"];
+    for county in &counties {
+        // Print the detected reporting days each time they change
+        let cadence = analysis::detectcadence(nytbycounty100k.get(*county).unwrap());
+        let mut days: Vec<&i32> = cadence.keys().collect();
+        days.sort();
+        let mut previous = None;
+        for day in days {
+            if previous != Some(cadence[day]) {
+                let weekdays: Vec<&str> = (0..7).filter(|i| cadence[day][*i]).map(|i| analysis::WEEKDAYS[i]).collect();
+                println!("{} County reports on: {} (from {})", county, weekdays.join(", "), day_to_nd(*day));
+                previous = Some(cadence[day]);
+            }
+        }
+    }
+
+    charts::write_categorical(
+        "weekday-effect-nyt",
+        out,
+        "Reported New COVID-19 Cases by Day of Week, Kansas (NYT)",
+        "Avg. new cases that weekday relative to avg. of all days",
+        &analysis::WEEKDAYS,
+        counties
+            .iter()
+            .map(|county| (*county, analysis::weekdayeffect(nytbycounty100k.get(*county).unwrap()).to_vec()))
+            .collect(),
+        &charts::ChartOptions::new().shape(charts::Shape::Linear).markers(true),
+    );
 
-    let deltconffra = db::getgeneralmaskdata_100k(
-        &pool,
-        "jhu/series",
-        "delta_confirmed",
-        "province = '' and country_code = 'FR' and location_type = 'total-country'",
+    let sedgwick = nytbycounty100k.get("Sedgwick").unwrap();
+    let redistributed = analysis::debatch(sedgwick);
+    charts::write_generic(
+        "weekday-redistributed-nyt",
+        out,
+        "Daily New COVID-19 Cases, Sedgwick County (NYT)",
+        "New cases per 100,000 pop.",
+        vec![("As reported", sedgwick), ("Batches redistributed", &redistributed)],
         first_date,
         last_date,
-    )
-    .await;
-    let deltconffra = analysis::calcsimplema(&deltconffra, 7);
+        &opts_100k(pops).shape(charts::Shape::Linear),
+    );
+
+    cache.record(&charts, &definition, &out.config);
+}
+
+async fn write_reconciliation(
+    pool: &SqlitePool,
+    cache: &mut cache::BuildCache,
+    out: &mut charts::Output,
+    threshold: f64,
+    first_date: i32,
+    last_date: i32,
+) {
+    let charts = ["reconcile-ks-daily", "reconcile-ks-diff", "reconcile-ks-total-diff", "reconcile-ks-days"];
+    let definition = format!("reconcile {} {} {}", threshold, first_date, last_date);
+    if cache.reuse(&charts, &definition, out) {
+        return;
+    }
+
+    let ks = "province = 'Kansas' and country_code = 'US' and location_type = 'total-province'";
+    let (nytnew, nyttotal, jhunew, jhutotal, ctnew, cttotal) = futures::join!(
+        db::getstatedata_nytcounties(pool, "Kansas", "cases_new", first_date, last_date),
+        db::getstatedata_nytcounties(pool, "Kansas", "cases", first_date, last_date),
+        db::getgeneraldata(pool, "jhu/daily", "delta_confirmed", ks, first_date, last_date),
+        db::getgeneraldata(pool, "jhu/daily", "absolute_confirmed", ks, first_date, last_date),
+        db::getfield_covidtracking(pool, "KS", "positiveIncrease", first_date, last_date),
+        db::getfield_covidtracking(pool, "KS", "positive", first_date, last_date),
+    );
 
-    let deltconftwn = db::getgeneralmaskdata_100k(
-        &pool,
-        "jhu/series",
-        "delta_confirmed",
-        "province = '' and country_code = 'TW' and location_type = 'total-country'",
+    charts::write_generic(
+        "reconcile-ks-daily",
+        out,
+        "New COVID-19 Cases in Kansas by Source",
+        "New cases",
+        vec![("NYT (sum of counties)", &nytnew), ("JHU", &jhunew), ("Covid Tracking", &ctnew)],
         first_date,
         last_date,
-    )
-    .await;
-    let deltconftwn = analysis::calcsimplema(&deltconftwn, 7);
-
+        &charts::ChartOptions::new().shape(charts::Shape::Linear),
+    );
 
+    let jhudiff = analysis::calcdifference(&jhunew, &nytnew);
+    let ctdiff = analysis::calcdifference(&ctnew, &nytnew);
     charts::write_generic(
-        "centralusa-100k",
-        bightml,
-        "New COVID-19 cases in Central USA (JHU)",
-        "7-day moving avg of new cases per 100,000 pop.",
-        vec![
-            ("Kansas", &deltconfks),
-            ("Missouri", &deltconfmo),
-            ("Colorado", &deltconfco),
-            ("Nebraska", &deltconfne),
-            ("Oklahoma", &deltconfok),
-            ("USA", &deltconfus),
-        ],
+        "reconcile-ks-diff",
+        out,
+        "Difference in New COVID-19 Cases in Kansas From NYT",
+        "New cases reported by source minus NYT",
+        vec![("JHU", &jhudiff), ("Covid Tracking", &ctdiff)],
         first_date,
         last_date,
+        &charts::ChartOptions::new().shape(charts::Shape::Linear),
     );
     charts::write_generic(
-        "global-100k",
-        bightml,
-        "New COVID-19 cases in Selected Regions (JHU + NYT where indicated)",
-        "7-day moving avg of new cases per 100,000 pop.",
+        "reconcile-ks-total-diff",
+        out,
+        "Difference in Total COVID-19 Cases in Kansas From NYT",
+        "Total cases reported by source minus NYT",
         vec![
-            ("Kansas", &deltconfks),
-            ("Sedgwick County (NYT)", nytbycounty100k.get("Sedgwick").unwrap()),
-            ("USA", &deltconfus),
-            ("Canada", &deltconfcan),
-            ("United Kingdom", &deltconfgbr),
-            ("France", &deltconffra),
-            ("Taiwan", &deltconftwn),
+            ("JHU", &analysis::calcdifference(&jhutotal, &nyttotal)),
+            ("Covid Tracking", &analysis::calcdifference(&cttotal, &nyttotal)),
         ],
         first_date,
         last_date,
+        &charts::ChartOptions::new().shape(charts::Shape::Linear),
     );
 
+    let fmt = |hm: &HashMap<i32, f64>, day: i32| hm.get(&day).map(|x| format!("{:.0}", x)).unwrap_or_default();
+    let rows: Vec<Vec<String>> = (first_date..=last_date)
+        .filter(|day| {
+            jhudiff.get(day).is_some_and(|x| x.abs() > threshold)
+                || ctdiff.get(day).is_some_and(|x| x.abs() > threshold)
+        })
+        .map(|day| {
+            vec![
+                day_to_nd(day).to_string(),
+                fmt(&nytnew, day),
+                fmt(&jhunew, day),
+                fmt(&ctnew, day),
+            ]
+        })
+        .collect();
+    println!("{} days with new case counts differing from NYT by more than {}", rows.len(), threshold);
+    charts::write_table(
+        "reconcile-ks-days",
+        out,
+        &format!("Days on which JHU or Covid Tracking differ from NYT by more than {} new cases in Kansas", threshold),
+        &["Date", "NYT", "JHU", "Covid Tracking"],
+        rows,
+    );
+
+    cache.record(&charts, &definition, &out.config);
 }
 
-async fn write_testing(pool: &SqlitePool, bightml: &mut File, first_date: i32, last_date: i32) {
-    let cttest_ks =
-        db::gettestdata(pool, "KS", first_date - 15, last_date).await;
+async fn write_testing(
+    pool: &SqlitePool,
+    cache: &mut cache::BuildCache,
+    out: &mut charts::Output,
+    pops: &localdata::PopulationOverrides,
+    first_date: i32,
+    last_date: i32,
+) {
+    let charts = ["test-global", "test-ks-methods", "tests-per-1000", "tests-per-case"];
+    let definition = format!("testing {} {} {:?}", first_date, last_date, pops);
+    if cache.reuse(&charts, &definition, out) {
+        return;
+    }
+
+    let countries = [("USA", "USA"), ("Canada", "CAN"), ("United Kingdom", "GBR"), ("France", "FRA"), ("Taiwan", "TWN")];
+    let (cttest_ks, owidtest, ksmethods, mut usmethods, countypop) = futures::join!(
+        db::gettestdata(pool, "KS", first_date - 15, last_date),
+        stream::iter(countries.iter())
+            .map(|(_, country)| async move {
+                let data = db::gettestdata_owid(pool, country, first_date - 15, last_date).await;
+                (*country, (data, db::getpopulation_owid(pool, pops, country).await))
+            })
+            .buffer_unordered(pool.max_size() as usize)
+            .collect::<HashMap<_, _>>(),
+        stream::iter(analysis::Positivity::ALL.iter())
+            .map(|method| async move {
+                let data = db::getpositivitydata_covidtracking(pool, "KS", *method, first_date - 15, last_date).await;
+                (method.name(), analysis::calcpositivity(&data, *method, 14, false))
+            })
+            .buffer_unordered(pool.max_size() as usize)
+            .collect::<HashMap<_, _>>(),
+        stream::iter(analysis::Positivity::ALL.iter().filter(|method| method.owid_columns().is_some()))
+            .map(|method| async move {
+                let data = db::getpositivitydata_owid(pool, "USA", *method, first_date - 15, last_date).await;
+                (format!("USA (OWID): {}", method.name()), analysis::calcpositivity(&data, *method, 14, false))
+            })
+            .buffer_unordered(pool.max_size() as usize)
+            .collect::<Vec<_>>(),
+        db::getcountypopulation_nytcounties(pool, pops),
+    );
     assert_eq!((723, 5578), *cttest_ks.get(&ymd_to_day(2020, 8, 19)).unwrap());
-    let cttest_ks = analysis::calcsimplerate_testdata(&cttest_ks, 14, false);
-
-    let owidtest_usa =
-        db::gettestdata_owid(pool, "USA", first_date - 15, last_date).await;
-    let owidtest_usa = analysis::calcsimplerate_testdata(&owidtest_usa, 14, false);
-
-    let owidtest_can =
-        db::gettestdata_owid(pool, "CAN", first_date - 15, last_date).await;
-    let owidtest_can = analysis::calcsimplerate_testdata(&owidtest_can, 14, false);
-    let owidtest_gbr =
-        db::gettestdata_owid(pool, "GBR", first_date - 15, last_date).await;
-    let owidtest_gbr = analysis::calcsimplerate_testdata(&owidtest_gbr, 14, false);
-    let owidtest_fra =
-        db::gettestdata_owid(pool, "FRA", first_date - 15, last_date).await;
-    let owidtest_fra = analysis::calcsimplerate_testdata(&owidtest_fra, 14, false);
-    let owidtest_twn =
-        db::gettestdata_owid(pool, "TWN", first_date - 15, last_date).await;
-    let owidtest_twn = analysis::calcsimplerate_testdata(&owidtest_twn, 14, false);
+
+    // Kansas first, then the countries, as (label, daily (pos, total), population)
+    let kspop = pops.get("state:Kansas").unwrap_or_else(|| countypop.values().sum());
+    let mut regions = vec![("Kansas (CT)", &cttest_ks, Some(kspop))];
+    regions.extend(countries.iter().map(|(label, country)| (*label, &owidtest[country].0, owidtest[country].1)));
+    let positivity: HashMap<&str, HashMap<i32, f64>> = regions
+        .iter()
+        .map(|(label, data, _)| (*label, analysis::calcpositivity(data, analysis::Positivity::Tests, 14, false)))
+        .collect();
+    for (label, _, pop) in &regions {
+        if pop.is_none() {
+            println!("No population for {}; leaving it off tests-per-1000", label);
+        }
+    }
 
     let cttest_recommended : HashMap<i32, f64> =
         // recommended rate is 5% per https://coronavirus.jhu.edu/testing/testing-positivity
@@ -283,21 +879,686 @@ async fn write_testing(pool: &SqlitePool, bightml: &mut File, first_date: i32, l
 
     charts::write_generic(
         "test-global",
-        bightml,
+        out,
         "COVID-19 Test Positivity Rate (OWID + Covid Tracking where indicated)",
         "14-day % of test results positive",
         vec![
-            ("Kansas (CT)", &cttest_ks),
-            ("USA", &owidtest_usa),
+            ("Kansas (CT)", &positivity["Kansas (CT)"]),
+            ("USA", &positivity["USA"]),
             ("Recommended Maximum", &cttest_recommended),
-            ("Canada", &owidtest_can),
-            ("United Kingdom", &owidtest_gbr),
-            ("France", &owidtest_fra),
-            ("Taiwan", &owidtest_twn),
+            ("Canada", &positivity["Canada"]),
+            ("United Kingdom", &positivity["United Kingdom"]),
+            ("France", &positivity["France"]),
+            ("Taiwan", &positivity["Taiwan"]),
         ],
         first_date,
         last_date,
+        &charts::ChartOptions::new(),
+    );
+
+    let mut series: Vec<(&str, &HashMap<i32, f64>)> =
+        analysis::Positivity::ALL.iter().map(|method| (method.name(), &ksmethods[method.name()])).collect();
+    // The USA by the methods OWID has the data for, for reference
+    usmethods.sort_by(|a, b| a.0.cmp(&b.0));
+    series.extend(usmethods.iter().map(|(label, data)| (label.as_str(), data)));
+    series.push(("Recommended Maximum", &cttest_recommended));
+    charts::write_generic(
+        "test-ks-methods",
+        out,
+        "COVID-19 Test Positivity Rate by Method, Kansas (Covid Tracking + OWID where indicated)",
+        "14-day % positive",
+        series,
+        first_date,
+        last_date,
+        &charts::ChartOptions::new(),
+    );
+
+    // Whether testing is keeping up with spread: more tests per person, and per case found,
+    // is better
+    let opts = out.config.apply("tests-per-1000", &opts_100k(pops).smoothing(MA7));
+    let perthousand: Vec<(&str, HashMap<i32, f64>)> = regions
+        .iter()
+        .filter_map(|(label, data, pop)| {
+            let pop = (*pop)?;
+            // Some countries report tests only now and then; fill in the days between
+            let daily = data
+                .iter()
+                .filter(|(_, (_, tot))| *tot > 0)
+                .map(|(day, (_, tot))| (*day, 1000.0 * (*tot as f64) / pop))
+                .collect();
+            Some((*label, opts.smoothing.smooth(&analysis::interpolate(&daily))))
+        })
+        .collect();
+    charts::write_generic(
+        "tests-per-1000",
+        out,
+        "COVID-19 Tests per 1,000 People (OWID + Covid Tracking where indicated)",
+        &smoothedaxis(&opts.smoothing, "tests per 1,000 pop."),
+        perthousand.iter().map(|(label, data)| (*label, data)).collect(),
+        first_date,
+        last_date,
+        &opts,
+    );
+
+    // Tests per case over the window is the reciprocal of positivity
+    let percase: Vec<(&str, HashMap<i32, f64>)> = regions
+        .iter()
+        .map(|(label, _, _)| {
+            let rate = &positivity[label];
+            (*label, rate.iter().filter(|(_, pct)| **pct > 0.0).map(|(day, pct)| (*day, 100.0 / pct)).collect())
+        })
+        .collect();
+    charts::write_generic(
+        "tests-per-case",
+        out,
+        "COVID-19 Tests per Confirmed Case (OWID + Covid Tracking where indicated)",
+        "14-day test results per positive result",
+        percase.iter().map(|(label, data)| (*label, data)).collect(),
+        first_date,
+        last_date,
+        &charts::ChartOptions::new().y_log(true),
+    );
+
+    cache.record(&charts, &definition, &out.config);
+}
+
+async fn write_classes(
+    pool: &SqlitePool,
+    cache: &mut cache::BuildCache,
+    out: &mut charts::Output,
+    pops: &localdata::PopulationOverrides,
+    classes: &[counties::CountyGroup],
+    first_date: i32,
+    last_date: i32,
+) {
+    let charts = ["classes-cases-100k-nyt", "classes-deaths-100k-nyt"];
+    let definition = format!("classes {} {} {:?} {:?}", first_date, last_date, classes, pops);
+    if cache.reuse(&charts, &definition, out) {
+        return;
+    }
+
+    let (mut cases, mut deaths) = futures::join!(
+        db::getgroupdata_100k_nytcounties(pool, pops, "cases_new", classes, first_date, last_date),
+        db::getgroupdata_100k_nytcounties(pool, pops, "deaths_new", classes, first_date, last_date),
+    );
+    debatchall(&mut cases);
+    debatchall(&mut deaths);
+
+    let names = classes.iter().map(|c| c.name.as_str()).collect();
+    let opts = out.config.apply("classes-cases-100k-nyt", &opts_100k(pops).smoothing(MA7));
+    charts::writecounties_100k(
+        "classes-cases-100k-nyt",
+        out,
+        "New COVID-19 cases by County Classification, Kansas (NYT)",
+        &smoothedaxis(&opts.smoothing, "new cases per 100,000 pop."),
+        &names,
+        &smoothall(&cases, &opts.smoothing),
+        first_date,
+        last_date,
+        &opts,
+    );
+    let opts = out.config.apply("classes-deaths-100k-nyt", &opts_100k(pops).smoothing(MA7));
+    charts::writecounties_100k(
+        "classes-deaths-100k-nyt",
+        out,
+        "New COVID-19 deaths by County Classification, Kansas (NYT)",
+        &smoothedaxis(&opts.smoothing, "new deaths per 100,000 pop."),
+        &names,
+        &smoothall(&deaths, &opts.smoothing),
+        first_date,
+        last_date,
+        &opts,
+    );
+
+    cache.record(&charts, &definition, &out.config);
+}
+
+async fn write_vaccinations(
+    pool: &SqlitePool,
+    cache: &mut cache::BuildCache,
+    out: &mut charts::Output,
+    pops: &localdata::PopulationOverrides,
+    first_date: i32,
+    last_date: i32,
+) {
+    // Kansas county figures are only charted if they are given in ksvaccinations.csv
+    let countyfile = "ksvaccinations.csv";
+    let countyvacc = localdata::load_county_vaccinations(countyfile);
+    // Releases of the database before vaccinations began have no figures for them
+    let owidvacc = db::hascolumns(
+        pool,
+        "owid",
+        &["people_vaccinated_per_hundred", "people_fully_vaccinated_per_hundred"],
+    )
+    .await;
+    if !owidvacc {
+        println!("This database has no OWID vaccination figures; skipping vaccinated-global and fullyvaccinated-global");
+    }
+    let mut charts = vec![];
+    if owidvacc {
+        charts.extend(&["vaccinated-global", "fullyvaccinated-global"]);
+    }
+    if !countyvacc.is_empty() {
+        charts.extend(&["vaccinated-counties", "fullyvaccinated-counties"]);
+    }
+    if charts.is_empty() {
+        return;
+    }
+    let definition = format!(
+        "vaccinations {} {} {:?} {}",
+        first_date,
+        last_date,
+        pops,
+        fs::read_to_string(countyfile).unwrap_or_default()
+    );
+    if cache.reuse(&charts, &definition, out) {
+        return;
+    }
+
+    let countries = [("USA", "USA"), ("Canada", "CAN"), ("United Kingdom", "GBR"), ("France", "FRA"), ("Taiwan", "TWN")];
+    let (vaccinated, fullyvaccinated, population) = futures::join!(
+        stream::iter(countries.iter().filter(|_| owidvacc))
+            .map(|(label, country)| async move {
+                (*label, db::getvaccdata_owid(pool, country, "people_vaccinated_per_hundred", first_date, last_date).await)
+            })
+            .buffer_unordered(pool.max_size() as usize)
+            .collect::<HashMap<_, _>>(),
+        stream::iter(countries.iter().filter(|_| owidvacc))
+            .map(|(label, country)| async move {
+                (*label, db::getvaccdata_owid(pool, country, "people_fully_vaccinated_per_hundred", first_date, last_date).await)
+            })
+            .buffer_unordered(pool.max_size() as usize)
+            .collect::<HashMap<_, _>>(),
+        db::getcountypopulation_nytcounties(pool, pops),
+    );
+
+    if owidvacc {
+        charts::write_generic(
+            "vaccinated-global",
+            out,
+            "People Vaccinated Against COVID-19 (OWID)",
+            "% of population with at least one dose",
+            countries.iter().map(|(label, _)| (*label, &vaccinated[label])).collect(),
+            first_date,
+            last_date,
+            &charts::ChartOptions::new(),
+        );
+        charts::write_generic(
+            "fullyvaccinated-global",
+            out,
+            "People Fully Vaccinated Against COVID-19 (OWID)",
+            "% of population fully vaccinated",
+            countries.iter().map(|(label, _)| (*label, &fullyvaccinated[label])).collect(),
+            first_date,
+            last_date,
+            &charts::ChartOptions::new(),
+        );
+    }
+
+    if !countyvacc.is_empty() {
+        let mut counties: Vec<&String> = countyvacc.keys().collect();
+        counties.sort();
+        let mut vaccinated: HashMap<String, HashMap<i32, f64>> = HashMap::new();
+        let mut fullyvaccinated: HashMap<String, HashMap<i32, f64>> = HashMap::new();
+        for county in &counties {
+            let pop = *population
+                .get(*county)
+                .unwrap_or_else(|| panic!("{}: no population for {} County", countyfile, county));
+            let data = &countyvacc[*county];
+            vaccinated.insert((*county).clone(), data.iter().map(|(day, (vacc, _))| (*day, 100.0 * vacc / pop)).collect());
+            fullyvaccinated.insert((*county).clone(), data.iter().map(|(day, (_, full))| (*day, 100.0 * full / pop)).collect());
+        }
+        let counties = counties.into_iter().map(|x| x.as_str()).collect();
+        charts::writecounties_100k(
+            "vaccinated-counties",
+            out,
+            "People Vaccinated Against COVID-19, Kansas Counties",
+            "% of population with at least one dose",
+            &counties,
+            &vaccinated,
+            first_date,
+            last_date,
+            &opts_100k(pops),
+        );
+        charts::writecounties_100k(
+            "fullyvaccinated-counties",
+            out,
+            "People Fully Vaccinated Against COVID-19, Kansas Counties",
+            "% of population fully vaccinated",
+            &counties,
+            &fullyvaccinated,
+            first_date,
+            last_date,
+            &opts_100k(pops),
+        );
+    }
+
+    cache.record(&charts, &definition, &out.config);
+}
+
+/// Longest delay from case to death we consider
+const CFR_MAXLAG: i32 = 42;
+
+/// Days of cases and deaths summed for each day's case fatality ratio
+const CFR_WINDOW: i32 = 28;
+
+/// Chart the case fatality ratio with deaths lagged behind cases, for counties and states.
+/// Each region's lag is the one at which its cases best predict its deaths, unless lag is
+/// given.
+#[allow(clippy::too_many_arguments)]
+async fn write_cfr(
+    pool: &SqlitePool,
+    cache: &mut cache::BuildCache,
+    out: &mut charts::Output,
+    pops: &localdata::PopulationOverrides,
+    groups: &[counties::CountyGroup],
+    lag: Option<i32>,
+    first_date: i32,
+    last_date: i32,
+) {
+    let charts = ["cfr-counties-nyt", "cfr-states-jhu"];
+    let definition = format!("cfr {} {} {:?} {:?} {:?}", first_date, last_date, groups, pops, lag);
+    if cache.reuse(&charts, &definition, out) {
+        return;
+    }
+
+    // Population cancels out of the ratio, so rates per 100k will do as well as counts
+    let fetch_first = first_date - CFR_MAXLAG - CFR_WINDOW;
+    let (cases, deaths, mut jhucases, mut jhudeaths) = futures::join!(
+        getnytdaily_100k(pool, pops, "cases_new", groups, fetch_first, last_date),
+        getnytdaily_100k(pool, pops, "deaths_new", groups, fetch_first, last_date),
+        getjhudata_100k(pool, pops, &JHU_REGIONS[..6], "delta_confirmed", fetch_first, last_date),
+        getjhudata_100k(pool, pops, &JHU_REGIONS[..6], "delta_deaths", fetch_first, last_date),
+    );
+    debatchall(&mut jhucases);
+    debatchall(&mut jhudeaths);
+
+    // Label and lagged CFR for each region
+    let lagged = |name: &str, cases: &HashMap<i32, f64>, deaths: &HashMap<i32, f64>| {
+        let regionlag = lag.or_else(|| analysis::bestlag(&MA7.smooth(cases), &MA7.smooth(deaths), CFR_MAXLAG))?;
+        println!("{}: deaths lag cases by {} days", name, regionlag);
+        Some((format!("{} ({}-day lag)", name, regionlag), analysis::laggedcfr(cases, deaths, regionlag, CFR_WINDOW)))
+    };
+    let yaxis = format!("% of cases, {} days of deaths over {} days of cases before", CFR_WINDOW, CFR_WINDOW);
+
+    let mut names = vec!["Sedgwick", "Harvey", "McPherson", "Marion"];
+    names.extend(groups.iter().map(|g| g.name.as_str()));
+    let series: Vec<(String, HashMap<i32, f64>)> =
+        names.iter().filter_map(|name| lagged(name, &cases[*name], &deaths[*name])).collect();
+    charts::write_generic(
+        "cfr-counties-nyt",
+        out,
+        "Lagged Case Fatality Ratio in Selected Counties, Kansas (NYT)",
+        &yaxis,
+        series.iter().map(|(label, data)| (label.as_str(), data)).collect(),
+        first_date,
+        last_date,
+        &charts::ChartOptions::new(),
+    );
+
+    let series: Vec<(String, HashMap<i32, f64>)> = JHU_REGIONS[..6]
+        .iter()
+        .filter_map(|(label, _, _)| lagged(label, &jhucases[label], &jhudeaths[label]))
+        .collect();
+    charts::write_generic(
+        "cfr-states-jhu",
+        out,
+        "Lagged Case Fatality Ratio in Central USA (JHU)",
+        &yaxis,
+        series.iter().map(|(label, data)| (label.as_str(), data)).collect(),
+        first_date,
+        last_date,
+        &charts::ChartOptions::new(),
     );
+
+    cache.record(&charts, &definition, &out.config);
+}
+
+/// The longest lead or lag, in days, considered between regions
+const LEADLAG_MAXLAG: i32 = 21;
+
+/// For each pair of regions, the lag at which one's smoothed incidence best correlates
+/// with the other's, printed and as a heatmap cell with row leading column by that lag
+fn leadlagcells(regions: &[&str], data: &HashMap<String, HashMap<i32, f64>>) -> Vec<Vec<Option<(f64, String)>>> {
+    regions
+        .iter()
+        .map(|a| {
+            regions
+                .iter()
+                .map(|b| {
+                    if a == b {
+                        return None;
+                    }
+                    let (lag, r) = analysis::leadlag(&data[*a], &data[*b], LEADLAG_MAXLAG)?;
+                    if lag > 0 {
+                        println!("{} leads {} by {} days (r = {:.2})", a, b, lag, r);
+                    }
+                    Some((lag as f64, format!("{}<br>r={:.2}", lag, r)))
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Chart which regions' incidence leads which, for counties and for states.  The regions
+/// may be chosen in charts.toml.
+async fn write_leadlag(
+    pool: &SqlitePool,
+    cache: &mut cache::BuildCache,
+    out: &mut charts::Output,
+    pops: &localdata::PopulationOverrides,
+    groups: &[counties::CountyGroup],
+    first_date: i32,
+    last_date: i32,
+) {
+    let charts = ["leadlag-counties-nyt", "leadlag-states-jhu"];
+    let definition = format!("leadlag {} {} {:?} {:?}", first_date, last_date, groups, pops);
+    if cache.reuse(&charts, &definition, out) {
+        return;
+    }
+
+    let (nytbycounty100k, mut jhu100k) = futures::join!(
+        getnytdaily_100k(pool, pops, "cases_new", groups, first_date, last_date),
+        getjhudata_100k(pool, pops, JHU_REGIONS, "delta_confirmed", first_date, last_date),
+    );
+    debatchall(&mut jhu100k);
+    let jhu100k: HashMap<String, HashMap<i32, f64>> =
+        jhu100k.into_iter().map(|(label, data)| (String::from(label), data)).collect();
+    let xaxis = "Lagging region";
+    let yaxis = "Leading region";
+    let zaxis = "Days row leads column";
+
+    let mut names = vec!["Sedgwick", "Harvey", "McPherson", "Marion"];
+    names.extend(groups.iter().map(|g| g.name.as_str()));
+    let opts = out.config.apply("leadlag-counties-nyt", &opts_100k(pops).smoothing(MA7).regions(names));
+    let regions: Vec<&str> = opts.regions.iter().map(|r| r.as_str()).collect();
+    for region in &regions {
+        assert!(nytbycounty100k.contains_key(*region), "leadlag-counties-nyt: no county or group {}", region);
+    }
+    let cells = leadlagcells(&regions, &smoothall(&nytbycounty100k, &opts.smoothing));
+    charts::write_heatmap(
+        "leadlag-counties-nyt",
+        out,
+        "Lead and Lag in New COVID-19 Cases Between Counties, Kansas (NYT)",
+        xaxis,
+        yaxis,
+        zaxis,
+        &regions,
+        cells,
+        &opts,
+    );
+
+    let names = JHU_REGIONS[..6].iter().map(|(label, _, _)| *label).collect();
+    let opts = out.config.apply("leadlag-states-jhu", &opts_100k(pops).smoothing(MA7).regions(names));
+    let regions: Vec<&str> = opts.regions.iter().map(|r| r.as_str()).collect();
+    for region in &regions {
+        assert!(jhu100k.contains_key(*region), "leadlag-states-jhu: no region {}", region);
+    }
+    let cells = leadlagcells(&regions, &smoothall(&jhu100k, &opts.smoothing));
+    charts::write_heatmap(
+        "leadlag-states-jhu",
+        out,
+        "Lead and Lag in New COVID-19 Cases Between States (JHU)",
+        xaxis,
+        yaxis,
+        zaxis,
+        &regions,
+        cells,
+        &opts,
+    );
+
+    cache.record(&charts, &definition, &out.config);
+}
+
+/// Days before a policy over which the trend it interrupted is fit
+const POLICY_BEFORE: i32 = 42;
+
+/// Days from a policy on over which its effect is fit
+const POLICY_AFTER: i32 = 56;
+
+/// Estimate the effect of each policy on new cases by segmented regression, against its
+/// comparison region by difference-in-differences if it has one.  Print the estimates,
+/// tabulate them, and chart the fits against what was observed.
+#[allow(clippy::too_many_arguments)]
+async fn write_policies(
+    pool: &SqlitePool,
+    cache: &mut cache::BuildCache,
+    out: &mut charts::Output,
+    pops: &localdata::PopulationOverrides,
+    groups: &[counties::CountyGroup],
+    policies: &[localdata::Policy],
+    last_date: i32,
+) {
+    let charts = ["policy-fitted-nyt", "policy-effects"];
+    let definition = format!("policies {} {:?} {:?} {:?}", last_date, groups, pops, policies);
+    if cache.reuse(&charts, &definition, out) {
+        return;
+    }
+
+    let fetch_first = policies.iter().map(|p| p.date).min().unwrap() - POLICY_BEFORE;
+    // Reporting batches would otherwise look like changes in level, so they are spread out
+    let nytbycounty100k = getnytdaily_100k(pool, pops, "cases_new", groups, fetch_first, last_date).await;
+    let region = |name: &str| {
+        nytbycounty100k
+            .get(name)
+            .unwrap_or_else(|| panic!("policies.csv: {} is not a Kansas county or a county group", name))
+    };
+
+    // Label, observed, fitted, counterfactual, and policy date for the chart
+    let mut fits = Vec::new();
+    let mut rows = Vec::new();
+    for policy in policies {
+        let treated = region(&policy.region);
+        let (label, observed, fit) = match &policy.comparison {
+            Some(comparison) => (
+                format!("{}: {} minus {}", policy.name, policy.region, comparison),
+                analysis::calcdifference(treated, region(comparison)),
+                intervention::diffindiff(treated, region(comparison), policy.date, POLICY_BEFORE, POLICY_AFTER),
+            ),
+            None => (
+                format!("{}: {}", policy.name, policy.region),
+                treated.clone(),
+                intervention::segmented(treated, policy.date, POLICY_BEFORE, POLICY_AFTER),
+            ),
+        };
+        let fit = match fit {
+            Some(fit) => fit,
+            None => {
+                println!("{}: not enough data around {} to estimate an effect", label, day_to_nd(policy.date));
+                continue;
+            }
+        };
+        let describe = |e: &intervention::Estimate| format!("{:.2} ({:.2} to {:.2})", e.value, e.lower, e.upper);
+        println!(
+            "{}: level change {}, slope change {} per day",
+            label,
+            describe(&fit.level),
+            describe(&fit.slope)
+        );
+        rows.push(vec![
+            policy.name.clone(),
+            policy.region.clone(),
+            policy.comparison.clone().unwrap_or_default(),
+            day_to_nd(policy.date).to_string(),
+            describe(&fit.level),
+            describe(&fit.slope),
+        ]);
+        fits.push((label, observed, fit, policy.date));
+    }
+
+    // The fits are to daily data, so that is what they are drawn against unless configured
+    // otherwise, in which case the chart says so
+    let mut opts = out.config.apply("policy-fitted-nyt", &opts_100k(pops).smoothing(Smoothing::None));
+    if opts.smoothing != Smoothing::None {
+        opts.note = Some(format!("Observed values are a {}; fits are to daily values", opts.smoothing.describe()));
+    }
+    let smoothed: Vec<HashMap<i32, f64>> = fits.iter().map(|(_, observed, _, _)| opts.smoothing.smooth(observed)).collect();
+    charts::write_fitted(
+        "policy-fitted-nyt",
+        out,
+        "New COVID-19 cases Around Policies, Observed and Fitted, Kansas (NYT)",
+        "Days since policy took effect",
+        &smoothedaxis(&opts.smoothing, "new cases per 100,000 pop."),
+        fits.iter()
+            .zip(&smoothed)
+            .map(|((label, _, fit, date), observed)| (label.as_str(), observed, &fit.fitted, &fit.counterfactual, *date))
+            .collect(),
+        POLICY_BEFORE,
+        last_date,
+        &opts,
+    );
+
+    charts::write_table(
+        "policy-effects",
+        out,
+        "Estimated Policy Effects on New Cases per 100,000 per Day, with 95% Confidence Intervals",
+        &["Policy", "Region", "Compared with", "Took effect", "Change in level", "Change in slope per day"],
+        rows,
+    );
+
+    cache.record(&charts, &definition, &out.config);
+}
+
+/// Compare each county with a policy against a synthetic control: the weighted average of
+/// other Kansas counties that best matches it before the policy.  Counties covered by any
+/// policy are left out of the synthetic controls.  Policies for groups of counties are
+/// skipped.
+#[allow(clippy::too_many_arguments)]
+async fn write_synthetic(
+    pool: &SqlitePool,
+    cache: &mut cache::BuildCache,
+    out: &mut charts::Output,
+    pops: &localdata::PopulationOverrides,
+    groups: &[counties::CountyGroup],
+    policies: &[localdata::Policy],
+    last_date: i32,
+) {
+    let charts = ["synthetic-control-nyt", "synthetic-control"];
+    let definition = format!("synthetic {} {:?} {:?} {:?}", last_date, groups, pops, policies);
+    if cache.reuse(&charts, &definition, out) {
+        return;
+    }
+
+    // The days the smoothing takes in before the first one charted, so that its window is
+    // full from the start
+    let opts = out.config.apply("synthetic-control-nyt", &opts_100k(pops).smoothing(MA7));
+    let lead = opts.smoothing.days() as i32;
+    let fetch_first = policies.iter().map(|p| p.date).min().unwrap() - POLICY_BEFORE - lead;
+    let nytbycounty100k = getnytdaily_100k(pool, pops, "cases_new", &[], fetch_first, last_date).await;
+    let smoothed = smoothall(&nytbycounty100k, &opts.smoothing);
+
+    let mut treated: HashSet<&str> = HashSet::new();
+    for policy in policies {
+        match groups.iter().find(|g| g.name == policy.region) {
+            Some(group) => treated.extend(group.clist.iter().map(|c| c.as_str())),
+            None => {
+                treated.insert(policy.region.as_str());
+            }
+        }
+    }
+    let donors: HashMap<String, HashMap<i32, f64>> =
+        smoothed.iter().filter(|(county, _)| !treated.contains(county.as_str())).map(|(k, v)| (k.clone(), v.clone())).collect();
+
+    // Label, observed, synthetic before, synthetic after, and policy date for the chart
+    let mut fits = Vec::new();
+    let mut rows = Vec::new();
+    for policy in policies.iter().filter(|p| smoothed.contains_key(&p.region)) {
+        let label = format!("{}: {}", policy.name, policy.region);
+        let observed = smoothed.get(&policy.region).unwrap();
+        let sc = match intervention::syntheticcontrol(observed, &donors, policy.date, POLICY_BEFORE) {
+            Some(sc) => sc,
+            None => {
+                println!("{}: not enough data before {} for a synthetic control", label, day_to_nd(policy.date));
+                continue;
+            }
+        };
+        let weights: Vec<String> = sc.weights.iter().map(|(county, w)| format!("{} {:.0}%", county, w * 100.0)).collect();
+        println!(
+            "{}: synthetic control {}; RMSPE {:.2} before, {:.2} after; mean gap {:.2}; placebo p {:.2}",
+            label,
+            weights.join(", "),
+            sc.rmspe_before,
+            sc.rmspe_after,
+            sc.gap,
+            sc.placebo_p
+        );
+        rows.push(vec![
+            policy.name.clone(),
+            policy.region.clone(),
+            day_to_nd(policy.date).to_string(),
+            weights.join(", "),
+            format!("{:.2}", sc.rmspe_before),
+            format!("{:.2}", sc.gap),
+            format!("{:.2}", sc.placebo_p),
+        ]);
+        let (before, after): (HashMap<i32, f64>, HashMap<i32, f64>) =
+            sc.synthetic.iter().partition(|(day, _)| **day < policy.date);
+        fits.push((label, observed, before, after, policy.date));
+    }
+
+    charts::write_fitted(
+        "synthetic-control-nyt",
+        out,
+        "New COVID-19 cases Around Policies, Compared with Synthetic Controls, Kansas (NYT)",
+        "Days since policy took effect",
+        &smoothedaxis(&opts.smoothing, "new cases per 100,000 pop."),
+        fits.iter()
+            .map(|(label, observed, before, after, date)| (label.as_str(), *observed, before, after, *date))
+            .collect(),
+        POLICY_BEFORE,
+        last_date,
+        &opts,
+    );
+
+    charts::write_table(
+        "synthetic-control",
+        out,
+        "Synthetic Control Comparisons, in New Cases per 100,000 per Day",
+        &["Policy", "County", "Took effect", "Synthetic control", "RMSPE before", "Mean gap after", "Placebo p"],
+        rows,
+    );
+
+    cache.record(&charts, &definition, &out.config);
+}
+
+/// Score how well our forecasts would have done on each day from first_asof on, for the
+/// counties and states we forecast on our charts.
+async fn print_backtest(pool: &SqlitePool, pops: &localdata::PopulationOverrides, first_asof: i32, last_date: i32) {
+    let fetch_first = first_asof - forecast::FITDAYS - 7;
+    let (mut nytbycounty100k, mut deltconf) = futures::join!(
+        db::getcountydata_100k_nytcounties(pool, pops, "cases_new", fetch_first, last_date),
+        getjhudata_100k(
+            pool,
+            pops,
+            &JHU_REGIONS[..6],
+            "delta_confirmed",
+            fetch_first,
+            last_date,
+        )
+    );
+    debatchall(&mut nytbycounty100k);
+    debatchall(&mut deltconf);
+    let mut regions: Vec<(&str, HashMap<i32, f64>)> = ["Marion", "Harvey", "Sedgwick", "McPherson"]
+        .iter()
+        .map(|county| (*county, MA7.smooth(nytbycounty100k.get(*county).unwrap())))
+        .collect();
+    for (region, _, _) in &JHU_REGIONS[..6] {
+        regions.push((region, MA7.smooth(&deltconf[region])));
+    }
+
+    println!("Forecast backtest: {}-day fit, forecasts made {} through {}", forecast::FITDAYS, day_to_nd(first_asof), day_to_nd(last_date));
+    println!("{:<12} {:>8} {:>9} {:>8} {:>9}", "Region", "Horizon", "Forecasts", "MAPE", "Coverage");
+    for (label, data) in regions {
+        for horizon in &[7, forecast::HORIZON] {
+            match forecast::backtest(&data, first_asof, last_date - horizon, forecast::FITDAYS, *horizon) {
+                Some(score) => println!(
+                    "{:<12} {:>7}d {:>9} {:>7.1}% {:>8.0}%",
+                    label, horizon, score.forecasts, score.mape, 100.0 * score.coverage
+                ),
+                None => println!("{:<12} {:>7}d {:>9}", label, horizon, 0),
+            }
+        }
+    }
 }
 
 #[tokio::main]
@@ -306,15 +1567,17 @@ async fn main() {
     let last_date = ymd_to_day(2020, 8, 3);
 
     let data_first_date = ymd_to_day(2020, 5, 29);
-    let data_last_date = dateutc_to_day(&datelocal_to_dateutc(&Local::today())) - 2;
 
-    let _daterange_output = first_date..=last_date;
-    let _daterange_full = data_first_date..=data_last_date;
-    let _daterange_updated = first_date..data_last_date;
-
-    let mut bightml = File::create("html-fragments/all.html").unwrap();
+    // Per-chart options may be overridden in charts.toml
+    let mut out = charts::Output {
+        bightml: File::create("html-fragments/all.html").unwrap(),
+        config: charts::ChartConfig::load("charts.toml", SMOOTHED_CHARTS),
+        written: Vec::new(),
+    };
 
-    let filename = match get_nth_arg(1) {
+    // Run as "covid19ks site [database]" to also assemble the website in deploy/
+    let site = get_nth_arg(1).map(|x| x == "site").unwrap_or(false);
+    let filename = match get_nth_arg(if site { 2 } else { 1 }) {
         Ok(x) => String::from(x.to_str().unwrap()),
         Err(_) => {
             println!("Database file not specified; trying covid19.db in current directory");
@@ -328,12 +1591,73 @@ async fn main() {
             filename
         )
     }
+    // Pass --verbose to see the full text of each query
+    db::set_verbose(has_flag("--verbose"));
     let pool = SqlitePool::builder()
         .max_size(5)
         .build(format!("sqlite::{}", filename).as_ref())
         .await
         .expect("Error building");
 
-    write_incidence_100k(&pool, &mut bightml, data_first_date, data_last_date).await;
-    write_testing(&pool, &mut bightml, ymd_to_day(2020, 6, 6), data_last_date).await;
+    let today = dateutc_to_day(&datelocal_to_dateutc(&Local::today()));
+    let data_last_date = chart_last_date(today, db::getlastdate(&pool).await);
+
+    let _daterange_output = first_date..=last_date;
+    let _daterange_full = data_first_date..=data_last_date;
+    let _daterange_updated = first_date..data_last_date;
+
+    // Pass --force to regenerate every chart even if its inputs are unchanged
+    let mut cache = cache::BuildCache::load(".buildcache", filename.as_str(), has_flag("--force"));
+
+    // Population estimates in population.csv replace those in the database
+    let pops = localdata::load_populations("population.csv");
+
+    // Additional county groups may be defined in countygroups.csv
+    let countypop = db::getcountypopulation_nytcounties(&pool, &pops).await;
+    let countynames: Vec<&str> = countypop.keys().map(|x| x.as_str()).collect();
+    let mut groups = counties::builtin_groups();
+    groups.extend(counties::load_groups("countygroups.csv", &countynames));
+
+    // Policies, such as mask ordinances, may be given in policies.csv
+    let policies = localdata::load_policies("policies.csv");
+
+    write_incidence_100k(&pool, &mut cache, &mut out, &pops, &groups, data_first_date, data_last_date).await;
+    // Outbreaks started well before data_first_date, so start from the beginning
+    write_aligned_100k(&pool, &mut cache, &mut out, &pops, &groups, &policies, ymd_to_day(2020, 3, 1), data_last_date).await;
+    write_cumulative(&pool, &mut cache, &mut out, &pops, &groups, ymd_to_day(2020, 3, 1), data_last_date).await;
+    write_reconciliation(&pool, &mut cache, &mut out, 50.0, ymd_to_day(2020, 3, 1), data_last_date).await;
+    write_testing(&pool, &mut cache, &mut out, &pops, ymd_to_day(2020, 6, 6), data_last_date).await;
+    write_vaccinations(&pool, &mut cache, &mut out, &pops, ymd_to_day(2020, 12, 1), data_last_date).await;
+
+    // Pass --weekday-diagnostic to chart the day-of-week reporting artifacts we correct for
+    if has_flag("--weekday-diagnostic") {
+        write_weekday_diagnostic(&pool, &mut cache, &mut out, &pops, data_first_date, data_last_date).await;
+    }
+
+    // Pass --cfr-lag=N to fix the delay from case to death at N days, rather than estimating it
+    let cfrlag = flag_value("--cfr-lag").map(|x| x.parse().expect("--cfr-lag must be a number of days"));
+    write_cfr(&pool, &mut cache, &mut out, &pops, &groups, cfrlag, data_first_date, data_last_date).await;
+    write_leadlag(&pool, &mut cache, &mut out, &pops, &groups, data_first_date, data_last_date).await;
+
+    // Urban vs. rural charts are only written if a classification is given in countyclasses.csv
+    let classes = counties::load_classes("countyclasses.csv");
+    if !classes.is_empty() {
+        write_classes(&pool, &mut cache, &mut out, &pops, &classes, data_first_date, data_last_date).await;
+    }
+
+    // Policy effects are only estimated if policies are given in policies.csv
+    if !policies.is_empty() {
+        write_policies(&pool, &mut cache, &mut out, &pops, &groups, &policies, data_last_date).await;
+        write_synthetic(&pool, &mut cache, &mut out, &pops, &groups, &policies, data_last_date).await;
+    }
+
+    cache.save();
+    if site {
+        site::assemble("deploy", &out.written);
+    }
+
+    // Pass --backtest to see how well our forecasts would have done in the past
+    if has_flag("--backtest") {
+        print_backtest(&pool, &pops, data_first_date, data_last_date).await;
+    }
 }
@@ -0,0 +1,395 @@
+/* Website index page
+
+Copyright (c) 2020 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+ */
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Write;
+
+/// A run of charts on the page, followed by the paragraphs that describe them.  It is left
+/// out if none of its charts were written.
+pub struct Block {
+    pub charts: &'static [&'static str],
+    pub text: &'static [&'static str],
+}
+
+/// A section of the page, under a heading of the given level (1 for the top)
+pub struct Section {
+    pub level: usize,
+    pub heading: &'static str,
+    /// Paragraphs before the first block
+    pub intro: &'static [&'static str],
+    pub blocks: &'static [Block],
+}
+
+/// Paragraphs at the top of the page
+const PREAMBLE: &[&str] = &[
+    "These charts are generated by the [covid19ks](https://github.com/jgoerzen/covid19ks) project by John Goerzen.  They are refreshed automatically at least daily.  Last update: {{ site.time | date_to_rfc822 }}",
+];
+
+/// The sections of the page, in order.  This is the registry of charts: every chart the
+/// program writes must have a place here, with its description, and charts::Output refuses
+/// to write one that doesn't.  Blocks whose charts weren't written are left out.
+pub const SECTIONS: &[Section] = &[
+    Section {
+        level: 1,
+        heading: "COVID-19 in Central Kansas Counties",
+        intro: &[],
+        blocks: &[Block {
+            charts: &["counties-100k-nyt"],
+            text: &[
+                "Dates of interest:\n\n- The City of Wichita (in Sedgwick County) adopted a face mask ordinance on July 3.\n- Harvey County's mask ordinance took effect July 15.\n- The Harvey County Fair happened July 30 - August 3 with little observation of COVID-19-safe practices.\n- KDHE Sec. Norman says it can take 1 to 2 incubation periods (so 2-4 weeks) for the effects of masks or other enhanced protocols to be visible in the numbers.  Because this chart uses a 7-day simple moving average, the effect is further delayed.\n- The Harvey County data had a downward correction in number of cases on January 22, reducing by over 100.  This explains the unnaturally low (and even negative) numbers you see in the 14 days after that.",
+                "The dashed lines at the end are a forecast of the next 14 days, assuming the trend of the last three weeks continues, and the shaded area around each is the range the forecast expects the actual number to fall in.  Forecasts like this can't anticipate changes in behavior or policy, so treat them as \"where things are headed if nothing changes,\" not a prediction.",
+                "Note that the higher the population of a county, the more useful the statistical data is for interpreting a trend.  Marion County looks bouncy because just one or two people can make a significant difference in the case rate even if it is hard to use to predict a trend.  The shaded area around each county's line shows this: it is the 95% confidence interval for the underlying rate, given how few cases there are to go on.  The smaller the county, the wider it is, and differences that fall within it may just be chance.",
+            ],
+        }],
+    },
+    Section {
+        level: 1,
+        heading: "COVID-19 in Groups of Kansas Counties",
+        intro: &[],
+        blocks: &[
+            Block {
+                charts: &["groups-100k-nyt"],
+                text: &[
+                    "Each group's rate is its member counties' total new cases divided by their total population, so larger counties count for more, just as they would if the group were a single county.  The Wichita metro is Sedgwick, Butler, Harvey, and Sumner counties.  The meatpacking counties are Ford, Finney, and Seward.",
+                ],
+            },
+            Block {
+                charts: &["classes-cases-100k-nyt", "classes-deaths-100k-nyt"],
+                text: &[
+                    "These graphs compare urban and rural counties, grouping every Kansas county by its classification.  As with the groups above, each class's rate is computed from the total cases or deaths and total population of its counties.",
+                ],
+            },
+        ],
+    },
+    Section {
+        level: 1,
+        heading: "Comparing Outbreaks and Mask Ordinances Across Counties",
+        intro: &[],
+        blocks: &[
+            Block {
+                charts: &["counties-aligned-100k-nyt"],
+                text: &[
+                    "Counties' outbreaks started at different times.  This graph lines them up by the number of days since each county's 7-day average first reached 1 new case per 100,000 people, so you can compare how quickly each one grew once it started.",
+                ],
+            },
+            Block {
+                charts: &["counties-since-mask-nyt"],
+                text: &[
+                    "This graph lines up counties by the number of days since a policy, such as a mask ordinance, took effect (the ordinance in Sedgwick County is the City of Wichita's), starting four weeks beforehand.",
+                ],
+            },
+            Block {
+                charts: &["policy-fitted-nyt", "policy-effects"],
+                text: &[
+                    "To put a number on a policy's effect, these fit a line to the six weeks of cases before it took effect and allow the line to jump and bend when it did.  The dashed lines are the fit; the dotted lines continue the earlier trend as if there had been no policy.  Where a comparison county is given, the fit is to the difference between the two, so that what they had in common isn't credited to the policy.  An effect whose confidence interval includes zero could well be chance.",
+                ],
+            },
+            Block {
+                charts: &["synthetic-control-nyt", "synthetic-control"],
+                text: &[
+                    "Another way to ask what would have happened without a policy: build a \"synthetic\" county, a weighted average of Kansas counties without the policy, that tracked the county closely for six weeks beforehand, and see how they differ afterward.  The dashed line is the synthetic county before the policy and the dotted line after it.  The placebo p-value is the share of counties that show as large a gap when each is treated as if it had had the policy; small values mean the gap would be unusual by chance.",
+                ],
+            },
+        ],
+    },
+    Section {
+        level: 1,
+        heading: "New COVID-19 Cases: Global Perspective",
+        intro: &[],
+        blocks: &[
+            Block {
+                charts: &["centralusa-100k"],
+                text: &[
+                    "This graph compares Kansas with its neighbors and with the USA as a whole.",
+                ],
+            },
+            Block {
+                charts: &["global-100k"],
+                text: &[
+                    "This graph is similar to the one before, but looks at things on a more global perspective.  It uses a logarithmic scale so that places with very few cases, such as Taiwan, can still be compared with the rest.  Again, notice how poorly the USA fares, and how Kansas is even worse.",
+                ],
+            },
+        ],
+    },
+    Section {
+        level: 1,
+        heading: "Total COVID-19 Cases and Deaths",
+        intro: &[],
+        blocks: &[Block {
+            charts: &[
+                "cumulative-counties-nyt",
+                "cumulative-deaths-counties-nyt",
+                "cumulative-global",
+                "cumulative-deaths-global",
+                "pct-confirmed",
+            ],
+            text: &[
+                "These graphs show the total number of cases and deaths since the start of the pandemic, relative to population.  The last shows the same totals as a percentage of the population that has had a confirmed case.  Many cases were never confirmed by a test, so the true percentage is higher.",
+            ],
+        }],
+    },
+    Section {
+        level: 1,
+        heading: "COVID-19 Vaccinations",
+        intro: &[],
+        blocks: &[
+            Block {
+                charts: &["vaccinated-global", "fullyvaccinated-global"],
+                text: &[
+                    "These graphs show the percentage of each country's population that has received at least one dose of a COVID-19 vaccine, and the percentage that is fully vaccinated.  Vaccination helps explain the changes in case rates during 2021.",
+                ],
+            },
+            Block {
+                charts: &["vaccinated-counties", "fullyvaccinated-counties"],
+                text: &["These graphs show the same for Kansas counties."],
+            },
+        ],
+    },
+    Section {
+        level: 1,
+        heading: "KSDE Metric Graphs",
+        intro: &[
+            "These charts pertain to the [school reopening metrics](https://www.ksde.org/Portals/0/Communications/Navigating%20Change/Gating%20Criteria%20Graphic.pdf?ver=2020-08-13-123107-083) set out by KSDE.",
+            "Some of the relevant data is above; additional data is here.",
+        ],
+        blocks: &[],
+    },
+    Section {
+        level: 2,
+        heading: "2-week cumulative county incidence rate",
+        intro: &[],
+        blocks: &[Block { charts: &["counties-100k-sum-nyt"], text: &[] }],
+    },
+    Section {
+        level: 3,
+        heading: "Kansas counties ranked",
+        intro: &[],
+        blocks: &[Block {
+            charts: &["counties-rank-nyt"],
+            text: &[
+                "Ranking counties by their raw rates puts the smallest ones at the top and bottom of the list, because a handful of cases moves their rates so much.  So this table ranks counties by a \"shrunk\" rate, which pulls each county toward the statewide rate in proportion to how little its own cases tell us.  Large counties barely move; small ones move a lot.  The observed rate is shown alongside.",
+            ],
+        }],
+    },
+    Section {
+        level: 2,
+        heading: "Case Fatality Ratio",
+        intro: &[],
+        blocks: &[Block {
+            charts: &["cfr-counties-nyt", "cfr-states-jhu"],
+            text: &[
+                "These graphs show the percentage of cases that ended in death.  People who die of COVID-19 usually do so weeks after they test positive, so each day's figure compares the deaths over the last 28 days with the cases over 28 days ending some days earlier.  That delay is estimated for each region from how closely its deaths follow its cases, and is shown in the legend.  Small counties have few deaths, so their ratios jump around a lot.",
+            ],
+        }],
+    },
+    Section {
+        level: 2,
+        heading: "Which Places Lead",
+        intro: &[],
+        blocks: &[Block {
+            charts: &["leadlag-counties-nyt", "leadlag-states-jhu"],
+            text: &[
+                "Each square shows how many days the region on its row runs ahead of the region in its column: the shift in time at which the two regions' 7-day average case rates line up best, up to three weeks either way.  A positive number means the row's cases rise and fall first.  The `r` beneath it is the correlation at that shift; values near 1 mean the two track each other closely, and a lead based on a low correlation means little.",
+            ],
+        }],
+    },
+    Section {
+        level: 2,
+        heading: "COVID-19 Test Positivity Rates - Local & Global Perspective",
+        intro: &[],
+        blocks: &[Block {
+            charts: &["test-global"],
+            text: &[
+                "This graph shows what percentage of all COVID-19 tests are coming back positive.  The WHO and CDC [recommend](https://coronavirus.jhu.edu/testing/testing-positivity) no more than 5% (the \"recommended maximum\" line on the chart).  Values above that indicate insufficient testing in the population and greater likelihood of undetected cases.",
+                "Note how many countries are doing better than the recommended, and, as of August 2020, how poorly both Kansas and the United States are doing.",
+                "You will observe an unusual peak in the Kansas data beginning July 27 and extending for 14 days.  There was an apparent data correction that [occurred](https://covidtracking.com/data/state/kansas) at KDHE on July 27, and so it reflects in the 14-day average from that date.  This is apparent in the KDHE screenshots from [July 26](https://covidtracking.com/screenshots/KS/KS-20200726-181019.png) and [July 27](https://covidtracking.com/screenshots/KS/KS-20200727-180947.png) that reflect a reduction of approximately 1000 negative cases and a corresponding reduction in the total test result count, leading to a very high calculated positivity rate on that one day.  Since we are using a 14-day window, this effect persists for 14 days.",
+            ],
+        }],
+    },
+    Section {
+        level: 3,
+        heading: "Positivity by method",
+        intro: &[],
+        blocks: &[Block {
+            charts: &["test-ks-methods"],
+            text: &[
+                "Not everyone computes positivity the same way, which is why the figures from KDHE, JHU, and others often differ from ours.  This graph shows Kansas positivity by several methods: positive test results over all test results, summed over 14 days (as in the chart above); the average of each day's ratio, which weights low-volume days as heavily as busy ones; viral (PCR) tests only, leaving out antibody tests; and people with a positive test over people tested, which counts a person tested repeatedly only once.  The US figures from OWID are shown for reference.",
+            ],
+        }],
+    },
+    Section {
+        level: 3,
+        heading: "Testing volume",
+        intro: &[],
+        blocks: &[Block {
+            charts: &["tests-per-1000", "tests-per-case"],
+            text: &[
+                "A low positivity rate can come from testing more, or from fewer people being sick.  These graphs show how much testing is being done: tests per 1,000 people each day, and the number of tests for every positive result over the last 14 days.  Where testing is keeping up with the spread of the virus, both are high.",
+            ],
+        }],
+    },
+    Section {
+        level: 1,
+        heading: "Comparing Data Sources for Kansas",
+        intro: &[],
+        blocks: &[
+            Block {
+                charts: &["reconcile-ks-daily", "reconcile-ks-diff", "reconcile-ks-total-diff"],
+                text: &[
+                    "The New York Times, Johns Hopkins University, and the COVID Tracking Project each collect Kansas numbers separately, and they don't always agree.  These graphs compare them, adding up the NYT county figures to get a statewide total.  Differences in timing -- one source counting cases a day later than another -- show up as matching spikes above and below zero; lasting differences show up in the difference in total cases.",
+                ],
+            },
+            Block { charts: &["reconcile-ks-days"], text: &[] },
+            Block {
+                charts: &["weekday-effect-nyt", "weekday-redistributed-nyt"],
+                text: &[
+                    "For much of the pandemic, Kansas counties reported cases only on certain weekdays.  The first graph shows how far each weekday's reports run above or below the week's average; the second shows one county's reports as made and with each batch spread back over the days it covers, as the county charts above do.",
+                ],
+            },
+        ],
+    },
+];
+
+/// Paragraphs at the bottom of the page
+const ABOUT: &[&str] = &[
+    "These charts are generated by the [covid19ks](https://github.com/jgoerzen/covid19ks) program using the [covid19db](https://github.com/jgoerzen/covid19db) database aggregation, both by John Goerzen.",
+    "The charts identified \"NYT\" use [data](https://github.com/nytimes/covid-19-data) from The New York Times, based on reports from state and local health agencies.",
+    "Charts identified \"COVID Tracking\" use [data](https://covidtracking.com/about-data/) from the COVID Tracking Project at The Atlantic.",
+    "Charts identified OWID use [data](https://ourworldindata.org/how-to-use-our-world-in-data#how-is-our-work-copyrighted) from Our World in Data.",
+    "Charts identified JHU use the [JHU CSSE COVID-19 Data](https://github.com/CSSEGISandData/COVID-19) from Johns Hopkins University.",
+];
+
+/// True if the chart has a place in SECTIONS
+pub fn registered(chart: &str) -> bool {
+    SECTIONS.iter().any(|section| section.blocks.iter().any(|block| block.charts.contains(&chart)))
+}
+
+/// Include a fragment from graphs/.  Kramdown is told to leave it alone, so that nothing in
+/// the HTML or script is taken for markdown.
+fn include(md: &mut String, chart: &str) {
+    md.push_str(&format!(
+        "{{::nomarkdown}}\n{{% include_relative graphs/{}.html %}}\n{{:/nomarkdown}}\n\n",
+        chart
+    ));
+}
+
+fn paragraphs(md: &mut String, text: &[&str]) {
+    for paragraph in text {
+        md.push_str(paragraph);
+        md.push_str("\n\n");
+    }
+}
+
+/// The anchor for a heading: lowercase letters and digits, with dashes between words
+fn slug(heading: &str) -> String {
+    heading
+        .to_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<&str>>()
+        .join("-")
+}
+
+/// Write the Jekyll index page for the website, with a table of contents and the charts
+/// that were written, with their descriptions.
+pub fn write_index(path: &str, written: &[String]) {
+    let written: HashSet<&str> = written.iter().map(|c| c.as_str()).collect();
+
+    // Level, heading, and body of each section shown
+    let mut shown: Vec<(usize, &str, String)> = Vec::new();
+    for section in SECTIONS {
+        let blocks: Vec<&Block> =
+            section.blocks.iter().filter(|b| b.charts.iter().any(|c| written.contains(c))).collect();
+        if blocks.is_empty() && !section.blocks.is_empty() {
+            continue;
+        }
+        let mut body = String::new();
+        paragraphs(&mut body, section.intro);
+        for block in blocks {
+            for chart in block.charts.iter().filter(|c| written.contains(*c)) {
+                include(&mut body, chart);
+            }
+            paragraphs(&mut body, block.text);
+        }
+        shown.push((section.level, section.heading, body));
+    }
+
+    let mut body = String::new();
+    paragraphs(&mut body, ABOUT);
+    shown.push((1, "About these charts", body));
+
+    let mut md = String::from("# Welcome to the Kansas COVID-19 graph page\n\n");
+    paragraphs(&mut md, PREAMBLE);
+    include(&mut md, "script");
+    md.push_str("Contents:\n\n");
+    for (level, heading, _) in &shown {
+        md.push_str(&format!("{}- [{}](#{})\n", "  ".repeat(level - 1), heading, slug(heading)));
+    }
+    md.push('\n');
+    for (level, heading, body) in &shown {
+        md.push_str(&format!("{} {} {{#{}}}\n\n", "#".repeat(*level), heading, slug(heading)));
+        md.push_str(body);
+    }
+    println!("Writing to {}", path);
+    File::create(path).unwrap().write_all(format!("{}\n", md.trim_end()).as_bytes()).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn charts_placed_once() {
+        let mut seen = HashSet::new();
+        for section in SECTIONS {
+            for block in section.blocks {
+                assert!(!block.charts.is_empty(), "a block under {} has no charts", section.heading);
+                for chart in block.charts {
+                    assert!(seen.insert(*chart), "{} is in SECTIONS twice", chart);
+                    assert!(registered(chart));
+                }
+            }
+        }
+        assert!(!registered("no-such-chart"));
+    }
+
+    #[test]
+    fn smoothed_charts_registered() {
+        for chart in crate::SMOOTHED_CHARTS {
+            assert!(registered(chart), "{} takes smoothing but has no place in SECTIONS", chart);
+        }
+    }
+
+    #[test]
+    fn index_shows_written_charts() {
+        let path = env::temp_dir().join("covid19ks-index.md");
+        write_index(path.to_str().unwrap(), &[String::from("cfr-states-jhu"), String::from("groups-100k-nyt")]);
+        let md = fs::read_to_string(&path).unwrap();
+        assert!(md.contains("{% include_relative graphs/groups-100k-nyt.html %}"));
+        assert!(md.contains("{% include_relative graphs/cfr-states-jhu.html %}"));
+        assert!(!md.contains("graphs/cfr-counties-nyt.html"));
+        // Sections with nothing written are left out, and the written ones are in order
+        assert!(!md.contains("# COVID-19 in Central Kansas Counties"));
+        let groups = md.find("# COVID-19 in Groups of Kansas Counties {#").unwrap();
+        assert!(groups < md.find("graphs/cfr-states-jhu.html").unwrap());
+    }
+}
@@ -0,0 +1,67 @@
+/* Website assembly
+
+Copyright (c) 2020 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+ */
+
+use crate::page;
+use std::fs;
+use std::path::Path;
+
+/// Copy the directory src into dest, which must not exist yet.
+fn copydir(src: &Path, dest: &Path) {
+    fs::create_dir(dest).unwrap_or_else(|e| panic!("Error creating {}: {}", dest.display(), e));
+    for entry in fs::read_dir(src).unwrap() {
+        let entry = entry.unwrap();
+        let target = dest.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            copydir(&entry.path(), &target);
+        } else {
+            fs::copy(entry.path(), &target).unwrap();
+        }
+    }
+}
+
+/// Copy an HTML fragment for inclusion in the page, as is; page::include keeps markdown
+/// from touching it.
+fn copyfragment(src: &str, dest: &Path) {
+    fs::copy(src, dest).unwrap_or_else(|e| panic!("Error copying {}: {}", src, e));
+}
+
+/// Assemble the website in dest, ready to publish: the contents of website/, the index
+/// page, and the fragments for the given charts along with the script they need.  A site
+/// assembled earlier in dest is replaced.
+pub fn assemble(dest: &str, written: &[String]) {
+    let dest = Path::new(dest);
+    if dest.exists() {
+        // Don't clobber anything but a site we assembled
+        if !dest.join("index.md").exists() || !dest.join("graphs").is_dir() {
+            panic!("{} exists and isn't an assembled site; move it out of the way", dest.display());
+        }
+        fs::remove_dir_all(dest).unwrap();
+    }
+    println!("Assembling website in {}", dest.display());
+    copydir(Path::new("website"), dest);
+
+    let graphs = dest.join("graphs");
+    fs::create_dir(&graphs).unwrap();
+    copyfragment("static/script.html", &graphs.join("script.html"));
+    for chart in written {
+        copyfragment(&format!("html-fragments/{}.html", chart), &graphs.join(format!("{}.html", chart)));
+    }
+
+    page::write_index(dest.join("index.md").to_str().unwrap(), written);
+}
@@ -0,0 +1,368 @@
+/* Smoothing
+
+Copyright (c) 2020 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+ */
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// What to do near the ends of a series, where a window would reach past the data
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Partial {
+    /// Leave out days whose window is incomplete
+    Drop,
+    /// Use only the days that are available
+    Shrink,
+    /// Count the missing days as zero.  This understates averages near the ends.
+    Zero,
+}
+
+/// A way of smoothing a daily series
+pub trait Smoother {
+    /// Smooth hm, which must have no gaps
+    fn smooth(&self, hm: &HashMap<i32, f64>) -> HashMap<i32, f64>;
+
+    /// What the smoothed values are, for axis labels, such as "7-day moving avg"
+    fn describe(&self) -> String;
+}
+
+/// Sort the series by day, making sure there are no gaps.  Returns the first day and
+/// the values in order.
+fn sorted(hm: &HashMap<i32, f64>) -> (i32, Vec<f64>) {
+    let mut keys: Vec<i32> = hm.keys().copied().collect();
+    keys.sort_unstable();
+    for pair in keys.windows(2) {
+        // Make sure we have no gaps in the data
+        assert_eq!(pair[0] + 1, pair[1]);
+    }
+    let first = keys.first().copied().unwrap_or_default();
+    (first, keys.iter().map(|key| hm[key]).collect())
+}
+
+/// Apply f to the window of before days before, and after days after, each day.
+fn windowed(
+    hm: &HashMap<i32, f64>,
+    before: usize,
+    after: usize,
+    partial: Partial,
+    f: impl Fn(&[f64]) -> f64,
+) -> HashMap<i32, f64> {
+    let (first, vals) = sorted(hm);
+    let mut rethm = HashMap::new();
+    for i in 0..vals.len() {
+        let missingbefore = before.saturating_sub(i);
+        let missingafter = (i + after + 1).saturating_sub(vals.len());
+        let window = &vals[i + missingbefore - before..=i + after - missingafter];
+        let val = match partial {
+            Partial::Drop if missingbefore > 0 || missingafter > 0 => continue,
+            Partial::Drop | Partial::Shrink => f(window),
+            Partial::Zero => {
+                let mut padded = vec![0.0; missingbefore];
+                padded.extend_from_slice(window);
+                padded.resize(before + after + 1, 0.0);
+                f(&padded)
+            }
+        };
+        rethm.insert(first + i as i32, val);
+    }
+    rethm
+}
+
+fn mean(window: &[f64]) -> f64 {
+    window.iter().sum::<f64>() / (window.len() as f64)
+}
+
+/// Leaves the series as is
+pub struct Unsmoothed;
+
+impl Smoother for Unsmoothed {
+    fn smooth(&self, hm: &HashMap<i32, f64>) -> HashMap<i32, f64> {
+        hm.clone()
+    }
+
+    fn describe(&self) -> String {
+        String::from("daily")
+    }
+}
+
+/// Mean of each day and the window - 1 days before it
+pub struct TrailingMean {
+    pub window: usize,
+    pub partial: Partial,
+}
+
+impl Smoother for TrailingMean {
+    fn smooth(&self, hm: &HashMap<i32, f64>) -> HashMap<i32, f64> {
+        windowed(hm, self.window - 1, 0, self.partial, mean)
+    }
+
+    fn describe(&self) -> String {
+        format!("{}-day moving avg", self.window)
+    }
+}
+
+/// Mean of a window centered on each day.  An even window has one more day before
+/// than after.
+pub struct CenteredMean {
+    pub window: usize,
+    pub partial: Partial,
+}
+
+impl Smoother for CenteredMean {
+    fn smooth(&self, hm: &HashMap<i32, f64>) -> HashMap<i32, f64> {
+        let before = self.window / 2;
+        windowed(hm, before, self.window - 1 - before, self.partial, mean)
+    }
+
+    fn describe(&self) -> String {
+        format!("{}-day centered moving avg", self.window)
+    }
+}
+
+/// Mean of each day and the window - 1 days before it, weighted linearly so that the
+/// most recent day counts window times as much as the oldest.
+pub struct WeightedMean {
+    pub window: usize,
+    pub partial: Partial,
+}
+
+impl Smoother for WeightedMean {
+    fn smooth(&self, hm: &HashMap<i32, f64>) -> HashMap<i32, f64> {
+        windowed(hm, self.window - 1, 0, self.partial, |window| {
+            let sum: f64 = window.iter().zip(1..).map(|(item, index)| item * (index as f64)).sum();
+            sum / ((window.len() * (window.len() + 1)) as f64 / 2.0)
+        })
+    }
+
+    fn describe(&self) -> String {
+        format!("{}-day weighted moving avg", self.window)
+    }
+}
+
+/// Exponential moving average, with a smoothing factor of 2 / (span + 1).  The first
+/// day's average is the first day's value under Shrink, or a fraction of it under Zero;
+/// Drop leaves out the first span - 1 days, while the average is still settling.
+pub struct ExponentialMean {
+    pub span: usize,
+    pub partial: Partial,
+}
+
+impl Smoother for ExponentialMean {
+    fn smooth(&self, hm: &HashMap<i32, f64>) -> HashMap<i32, f64> {
+        let alpha = 2.0 / (self.span as f64 + 1.0);
+        let (first, vals) = sorted(hm);
+        let mut rethm = HashMap::new();
+        let mut ema = None;
+        for (i, val) in vals.iter().enumerate() {
+            let next = match (ema, self.partial) {
+                (Some(prev), _) => alpha * val + (1.0 - alpha) * prev,
+                (None, Partial::Zero) => alpha * val,
+                (None, _) => *val,
+            };
+            ema = Some(next);
+            if self.partial != Partial::Drop || i + 1 >= self.span {
+                rethm.insert(first + i as i32, next);
+            }
+        }
+        rethm
+    }
+
+    fn describe(&self) -> String {
+        format!("{}-day exponential moving avg", self.span)
+    }
+}
+
+/// Sum of each day and the window - 1 days before it.  Shrink and Zero are the same
+/// for a sum.
+pub struct RollingSum {
+    pub window: usize,
+    pub partial: Partial,
+}
+
+impl Smoother for RollingSum {
+    fn smooth(&self, hm: &HashMap<i32, f64>) -> HashMap<i32, f64> {
+        windowed(hm, self.window - 1, 0, self.partial, |window| window.iter().sum())
+    }
+
+    fn describe(&self) -> String {
+        format!("{}-day sum", self.window)
+    }
+}
+
+/// The smoothing strategy for a chart, as named in ChartOptions and charts.toml, for
+/// instance:
+///
+/// ```toml
+/// [counties-100k-nyt]
+/// smoothing = { method = "centered", window = 7, partial = "drop" }
+/// ```
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(tag = "method", rename_all = "lowercase")]
+pub enum Smoothing {
+    None,
+    Trailing { window: usize, partial: Partial },
+    Centered { window: usize, partial: Partial },
+    Weighted { window: usize, partial: Partial },
+    Exponential { span: usize, partial: Partial },
+    Sum { window: usize, partial: Partial },
+}
+
+impl Smoothing {
+    /// Roughly how many days of data go into each smoothed value.  The smoothed value is
+    /// their sum for Sum, and their mean otherwise.
+    pub fn days(&self) -> usize {
+        match *self {
+            Smoothing::None => 1,
+            Smoothing::Trailing { window, .. }
+            | Smoothing::Centered { window, .. }
+            | Smoothing::Weighted { window, .. }
+            | Smoothing::Sum { window, .. } => window,
+            Smoothing::Exponential { span, .. } => span,
+        }
+    }
+
+    /// Check that the window or span covers at least one day.
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            Smoothing::None => Ok(()),
+            Smoothing::Exponential { span: 0, .. } => Err(String::from("span must be greater than 0")),
+            Smoothing::Exponential { .. } => Ok(()),
+            _ if self.days() == 0 => Err(String::from("window must be at least 1")),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn smoother(&self) -> Box<dyn Smoother> {
+        match *self {
+            Smoothing::None => Box::new(Unsmoothed),
+            Smoothing::Trailing { window, partial } => Box::new(TrailingMean { window, partial }),
+            Smoothing::Centered { window, partial } => Box::new(CenteredMean { window, partial }),
+            Smoothing::Weighted { window, partial } => Box::new(WeightedMean { window, partial }),
+            Smoothing::Exponential { span, partial } => Box::new(ExponentialMean { span, partial }),
+            Smoothing::Sum { window, partial } => Box::new(RollingSum { window, partial }),
+        }
+    }
+}
+
+impl Smoother for Smoothing {
+    fn smooth(&self, hm: &HashMap<i32, f64>) -> HashMap<i32, f64> {
+        self.smoother().smooth(hm)
+    }
+
+    fn describe(&self) -> String {
+        self.smoother().describe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{assert_close, series};
+
+    #[test]
+    fn trailing_partial_policies() {
+        let hm = series(&[3.0, 6.0, 9.0, 12.0]);
+        let smooth = |partial| TrailingMean { window: 3, partial }.smooth(&hm);
+        assert_close(&smooth(Partial::Drop), &[(102, 6.0), (103, 9.0)]);
+        assert_close(&smooth(Partial::Shrink), &[(100, 3.0), (101, 4.5), (102, 6.0), (103, 9.0)]);
+        assert_close(&smooth(Partial::Zero), &[(100, 1.0), (101, 3.0), (102, 6.0), (103, 9.0)]);
+    }
+
+    #[test]
+    fn centered_partial_policies() {
+        let hm = series(&[3.0, 6.0, 9.0, 12.0]);
+        let smooth = |partial| CenteredMean { window: 3, partial }.smooth(&hm);
+        assert_close(&smooth(Partial::Drop), &[(101, 6.0), (102, 9.0)]);
+        assert_close(&smooth(Partial::Shrink), &[(100, 4.5), (101, 6.0), (102, 9.0), (103, 10.5)]);
+        assert_close(&smooth(Partial::Zero), &[(100, 3.0), (101, 6.0), (102, 9.0), (103, 7.0)]);
+    }
+
+    #[test]
+    fn centered_even_window() {
+        // Two days before and one after
+        let hm = series(&[4.0, 8.0, 12.0, 16.0, 20.0]);
+        let smoothed = CenteredMean { window: 4, partial: Partial::Drop }.smooth(&hm);
+        assert_close(&smoothed, &[(102, 10.0), (103, 14.0)]);
+    }
+
+    #[test]
+    fn weighted_partial_policies() {
+        let hm = series(&[3.0, 6.0, 9.0]);
+        let smooth = |partial| WeightedMean { window: 3, partial }.smooth(&hm);
+        // (3 + 12 + 27) / 6
+        assert_close(&smooth(Partial::Drop), &[(102, 7.0)]);
+        // (3 + 12) / 3 on the second day
+        assert_close(&smooth(Partial::Shrink), &[(100, 3.0), (101, 5.0), (102, 7.0)]);
+        // (2 * 3 + 3 * 6) / 6 on the second day
+        assert_close(&smooth(Partial::Zero), &[(100, 1.5), (101, 4.0), (102, 7.0)]);
+    }
+
+    #[test]
+    fn exponential_partial_policies() {
+        // span 3 gives a smoothing factor of 0.5
+        let hm = series(&[4.0, 8.0, 8.0]);
+        let smooth = |partial| ExponentialMean { span: 3, partial }.smooth(&hm);
+        assert_close(&smooth(Partial::Shrink), &[(100, 4.0), (101, 6.0), (102, 7.0)]);
+        assert_close(&smooth(Partial::Drop), &[(102, 7.0)]);
+        assert_close(&smooth(Partial::Zero), &[(100, 2.0), (101, 5.0), (102, 6.5)]);
+    }
+
+    #[test]
+    fn sum_partial_policies() {
+        let hm = series(&[1.0, 2.0, 3.0, 4.0]);
+        let smooth = |partial| RollingSum { window: 2, partial }.smooth(&hm);
+        assert_close(&smooth(Partial::Drop), &[(101, 3.0), (102, 5.0), (103, 7.0)]);
+        assert_close(&smooth(Partial::Shrink), &[(100, 1.0), (101, 3.0), (102, 5.0), (103, 7.0)]);
+        assert_close(&smooth(Partial::Zero), &[(100, 1.0), (101, 3.0), (102, 5.0), (103, 7.0)]);
+    }
+
+    #[test]
+    fn short_series() {
+        // Windows longer than the series reach past both ends at once
+        let hm = series(&[2.0, 4.0]);
+        assert!(CenteredMean { window: 7, partial: Partial::Drop }.smooth(&hm).is_empty());
+        assert_close(&CenteredMean { window: 7, partial: Partial::Shrink }.smooth(&hm), &[(100, 3.0), (101, 3.0)]);
+        assert!(Smoothing::None.smooth(&HashMap::new()).is_empty());
+    }
+
+    #[test]
+    #[should_panic]
+    fn gaps_rejected() {
+        let hm: HashMap<i32, f64> = vec![(100, 1.0), (102, 1.0)].into_iter().collect();
+        TrailingMean { window: 2, partial: Partial::Shrink }.smooth(&hm);
+    }
+
+    #[test]
+    fn empty_windows_invalid() {
+        assert!(Smoothing::Trailing { window: 0, partial: Partial::Shrink }.validate().is_err());
+        assert!(Smoothing::Sum { window: 0, partial: Partial::Drop }.validate().is_err());
+        assert!(Smoothing::Exponential { span: 0, partial: Partial::Shrink }.validate().is_err());
+        assert!(Smoothing::Centered { window: 1, partial: Partial::Drop }.validate().is_ok());
+        assert!(Smoothing::None.validate().is_ok());
+    }
+
+    #[test]
+    fn named_in_config() {
+        let smoothing: Smoothing = toml::from_str("method = \"centered\"\nwindow = 7\npartial = \"drop\"").unwrap();
+        assert_eq!(smoothing, Smoothing::Centered { window: 7, partial: Partial::Drop });
+        assert_eq!(smoothing.describe(), "7-day centered moving avg");
+        let value = toml::Value::try_from(smoothing).unwrap();
+        assert_eq!(value.try_into::<Smoothing>().unwrap(), smoothing);
+        assert_eq!(toml::Value::try_from(Smoothing::None).unwrap().try_into::<Smoothing>().unwrap(), Smoothing::None);
+    }
+}
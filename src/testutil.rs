@@ -0,0 +1,33 @@
+/* Test helpers
+
+Copyright (c) 2020 John Goerzen
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+ */
+
+use std::collections::HashMap;
+
+/// Days 100 through 100 + vals.len() - 1
+pub fn series(vals: &[f64]) -> HashMap<i32, f64> {
+    vals.iter().enumerate().map(|(i, val)| (100 + i as i32, *val)).collect()
+}
+
+/// Assert that hm has exactly the expected days, with values equal to within rounding
+pub fn assert_close(hm: &HashMap<i32, f64>, expected: &[(i32, f64)]) {
+    assert_eq!(hm.len(), expected.len(), "{:?}", hm);
+    for (day, val) in expected {
+        assert!((hm[day] - val).abs() < 1e-9, "day {}: {} != {}", day, hm[day], val);
+    }
+}